@@ -0,0 +1,365 @@
+use std::{
+    collections::HashSet,
+    fmt::Display,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
+
+use argh::FromArgs;
+use serde::Serialize;
+use static_reload::Resource;
+
+use crate::{config::Config, metadata::xdg};
+
+#[derive(FromArgs)]
+/// check the configuration without starting the daemon
+#[argh(subcommand, name = "check-config")]
+pub struct CheckConfig {
+    /// print diagnostics as JSON
+    #[argh(switch)]
+    json: bool,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Serialize)]
+struct Diagnostic {
+    severity: Severity,
+    check: &'static str,
+    subject: String,
+    message: String,
+}
+
+#[derive(Default)]
+struct Report {
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Report {
+    fn push(
+        &mut self,
+        severity: Severity,
+        check: &'static str,
+        subject: impl Display,
+        message: impl Display,
+    ) {
+        self.diagnostics.push(Diagnostic {
+            severity,
+            check,
+            subject: subject.to_string(),
+            message: message.to_string(),
+        });
+    }
+
+    fn error(&mut self, check: &'static str, subject: impl Display, message: impl Display) {
+        self.push(Severity::Error, check, subject, message)
+    }
+
+    fn warning(&mut self, check: &'static str, subject: impl Display, message: impl Display) {
+        self.push(Severity::Warning, check, subject, message)
+    }
+
+    fn count(&self, severity: Severity) -> usize {
+        self.diagnostics
+            .iter()
+            .filter(|x| x.severity == severity)
+            .count()
+    }
+
+    fn to_json(&self) -> String {
+        // Serializing plain strings and enums cannot fail
+        serde_json::to_string_pretty(&self.diagnostics).unwrap()
+    }
+
+    fn exit_code(&self) -> ExitCode {
+        match self.count(Severity::Error) {
+            0 => ExitCode::SUCCESS,
+            _ => ExitCode::FAILURE,
+        }
+    }
+
+    fn print(&self, json: bool) {
+        if json {
+            println!("{}", self.to_json());
+            return;
+        }
+
+        for d in &self.diagnostics {
+            let severity = match d.severity {
+                Severity::Error => "error",
+                Severity::Warning => "warning",
+            };
+            println!("{severity}: [{}] {}: {}", d.check, d.subject, d.message);
+        }
+
+        println!(
+            "{} error(s), {} warning(s)",
+            self.count(Severity::Error),
+            self.count(Severity::Warning)
+        );
+    }
+}
+
+fn check_seat_backend(report: &mut Report, config: &Config) {
+    if let Some(backend) = config.backends.seat
+        && !backend.compiled_in()
+    {
+        report.error("seat-backend", backend.name(), "support is not compiled in");
+    }
+}
+
+async fn check_user_providers(report: &mut Report, config: &Config) {
+    let providers = config.user_providers();
+
+    if providers.is_empty() {
+        report.error("user-providers", "chain", "no user providers are available");
+    }
+
+    for kind in providers {
+        if let Err(e) = kind.connect().await {
+            report.error("user-providers", kind.name(), format!("{e:#}"));
+        }
+    }
+}
+
+// Returns the names of all sessions that parsed successfully
+fn check_sessions(report: &mut Report, config: &Config) -> HashSet<String> {
+    let mut found = HashSet::new();
+
+    for dir in &config.sessions.paths {
+        let entries = match fs_err::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                report.warning("sessions", dir.display(), "directory does not exist");
+                continue;
+            }
+            Err(e) => {
+                report.error("sessions", dir.display(), e);
+                continue;
+            }
+        };
+
+        for entry in entries {
+            let path: PathBuf = match entry {
+                Ok(entry) => entry.path(),
+                Err(e) => {
+                    report.error("sessions", dir.display(), e);
+                    continue;
+                }
+            };
+
+            if path.extension().is_none_or(|x| x != "desktop") {
+                continue;
+            }
+
+            let Some(name) = path.file_stem().and_then(|x| x.to_str()) else {
+                report.warning("sessions", path.display(), "file name is not valid UTF-8");
+                continue;
+            };
+
+            match xdg::get_entry(dir, name) {
+                Ok(_) => {
+                    found.insert(name.to_string());
+                }
+                Err(e) => report.error("sessions", path.display(), format!("{e:#}")),
+            }
+        }
+    }
+
+    found
+}
+
+fn check_autologin(report: &mut Report, config: &Config, sessions: &HashSet<String>) {
+    let global = config.autologin.iter().map(|x| ("global".to_string(), x));
+    let per_seat = config
+        .seats
+        .iter()
+        .filter_map(|(id, seat)| Some((format!("seat {id}"), seat.autologin.as_ref()?)));
+
+    for (scope, autologin) in global.chain(per_seat) {
+        if !sessions.contains(&autologin.session) {
+            report.error(
+                "autologin",
+                scope,
+                format!("session '{}' was not found", autologin.session),
+            );
+        }
+    }
+}
+
+async fn check(path: &Path) -> Report {
+    let mut report = Report::default();
+
+    if !path.exists() {
+        report.warning(
+            "config",
+            path.display(),
+            "file does not exist, defaults are used",
+        );
+    }
+
+    match Config::load(&path.to_path_buf()).await {
+        Ok(config) => {
+            check_seat_backend(&mut report, &config);
+            check_user_providers(&mut report, &config).await;
+            let sessions = check_sessions(&mut report, &config);
+            check_autologin(&mut report, &config, &sessions);
+        }
+        Err(e) => report.error("config", path.display(), format!("{e:#}")),
+    }
+
+    report
+}
+
+pub async fn run(path: &Path, args: CheckConfig) -> ExitCode {
+    let report = check(path).await;
+    report.print(args.json);
+    report.exit_code()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use serde_json::Value;
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::seat::SeatBackend;
+
+    const GOOD_SESSION: &str = "Type=Application\nName=Good\nExec=/bin/true\n";
+
+    // A config file next to a sessions directory of its own
+    struct Setup {
+        dir: TempDir,
+    }
+
+    impl Setup {
+        fn new() -> Self {
+            let dir = tempfile::tempdir().unwrap();
+            fs::create_dir(dir.path().join("sessions")).unwrap();
+            Self { dir }
+        }
+
+        fn session(&self, name: &str, text: &str) {
+            let path = self
+                .dir
+                .path()
+                .join("sessions")
+                .join(format!("{name}.desktop"));
+            fs::write(path, text).unwrap();
+        }
+
+        // config must not have a [sessions] table, that one points at the directory
+        async fn check(&self, config: &str) -> Report {
+            let path = self.dir.path().join("flint.toml");
+            let sessions = self.dir.path().join("sessions");
+
+            let text = format!("version = 1\n{config}\n[sessions]\npaths = [{sessions:?}]\n");
+            fs::write(&path, text).unwrap();
+
+            check(&path).await
+        }
+    }
+
+    // As check-config --json prints them
+    fn errors(report: &Report, check: &str) -> Vec<Value> {
+        let diagnostics: Vec<Value> = serde_json::from_str(&report.to_json()).unwrap();
+
+        diagnostics
+            .into_iter()
+            .filter(|x| x["severity"] == "error" && x["check"] == check)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn reports_bad_session_files() {
+        let setup = Setup::new();
+        setup.session("good", GOOD_SESSION);
+        setup.session("broken", "Name=Broken\n");
+
+        let report = setup.check("").await;
+        let errors = errors(&report, "sessions");
+
+        assert_eq!(errors.len(), 1);
+        assert!(
+            errors[0]["subject"]
+                .as_str()
+                .unwrap()
+                .ends_with("broken.desktop")
+        );
+        assert!(errors[0]["message"].as_str().unwrap().contains("not found"));
+        assert!(report.exit_code() == ExitCode::FAILURE);
+    }
+
+    #[tokio::test]
+    async fn reports_autologin_sessions_that_do_not_exist() {
+        let setup = Setup::new();
+        setup.session("good", GOOD_SESSION);
+
+        let report = setup
+            .check(
+                "[autologin]\nuser = \"alice\"\nsession = \"missing\"\n\
+                 [seats.seat1.autologin]\nuser = \"bob\"\nsession = \"good\"\n",
+            )
+            .await;
+        let errors = errors(&report, "autologin");
+
+        // The seat has one that exists
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0]["subject"], "global");
+        assert_eq!(errors[0]["message"], "session 'missing' was not found");
+        assert!(report.exit_code() == ExitCode::FAILURE);
+    }
+
+    #[tokio::test]
+    async fn reports_seat_backends_not_compiled_in() {
+        // Only testable in builds which leave a backend out
+        let Some(missing) = SeatBackend::ALL.into_iter().find(|x| !x.compiled_in()) else {
+            return;
+        };
+
+        let setup = Setup::new();
+        let report = setup
+            .check(&format!("[backends]\nseat = \"{}\"\n", missing.name()))
+            .await;
+        let errors = errors(&report, "seat-backend");
+
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0]["subject"], missing.name());
+        assert!(report.exit_code() == ExitCode::FAILURE);
+    }
+
+    #[tokio::test]
+    async fn reports_invalid_configs() {
+        let setup = Setup::new();
+        let report = setup.check("greeter = \"nonexistent\"\n").await;
+
+        assert_eq!(errors(&report, "config").len(), 1);
+        assert!(report.exit_code() == ExitCode::FAILURE);
+    }
+
+    // NSS is always there, unlike userdb which needs a running systemd
+    #[cfg(feature = "nss")]
+    #[tokio::test]
+    async fn passes_good_configs() {
+        let setup = Setup::new();
+        setup.session("good", GOOD_SESSION);
+
+        let report = setup
+            .check(
+                "[backends]\nusers = [\"nss\"]\n\
+                 [autologin]\nuser = \"alice\"\nsession = \"good\"\n",
+            )
+            .await;
+
+        assert_eq!(report.count(Severity::Error), 0);
+        assert!(report.exit_code() == ExitCode::SUCCESS);
+    }
+}
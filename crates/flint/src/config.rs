@@ -0,0 +1,306 @@
+use std::{collections::HashMap, io::ErrorKind, path::PathBuf};
+
+use anyhow::{Context, Result, bail, ensure};
+use serde::{Deserialize, Serialize};
+use static_reload::{Resource, ResourceCell};
+use toml::{Table, Value};
+use tracing::warn;
+
+use crate::{
+    core::{Runtime, SessionClass, UserClassFlags},
+    greet::GreeterKind,
+    metadata::xdg,
+    seat::{SeatBackend, SeatID},
+    user::ProviderKind,
+};
+
+pub const CURRENT_VERSION: i64 = 1;
+
+// Every field is optional, and unknown keys are an error.
+// NOTE: #[serde(flatten)] is not compatible with deny_unknown_fields, do not use it here
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub version: i64,
+    pub backends: Backends,
+    pub greeter: GreeterKind,
+    pub sessions: Sessions,
+    pub autologin: Option<Autologin>,
+
+    // Keyed by seat id
+    pub seats: HashMap<String, SeatOverride>,
+
+    // Keyed by username
+    pub users: HashMap<String, UserClassFlags>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Backends {
+    // If unset, the backend is probed at startup
+    pub seat: Option<SeatBackend>,
+
+    // Tried in order. If unset, every compiled-in provider is used.
+    pub users: Option<Vec<ProviderKind>>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Sessions {
+    // The runtime of sessions which do not specify one
+    pub runtime: Option<Runtime>,
+
+    // Where to look for session definitions, in order
+    pub paths: Vec<PathBuf>,
+
+    // Like a session definition, for the login shells text greeters start
+    pub shell: UserClassFlags,
+
+    // A user unit to start instead of the login shell, i.e. for a kiosk.
+    // The session lasts until the unit stops.
+    pub unit: Option<String>,
+}
+
+impl Default for Sessions {
+    fn default() -> Self {
+        Self {
+            runtime: None,
+            shell: UserClassFlags::default(),
+            unit: None,
+            paths: vec![
+                xdg::WAYLAND_SESSION_PATH.into(),
+                xdg::X11_SESSION_PATH.into(),
+            ],
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Autologin {
+    pub user: String,
+    // Name of the session definition to start
+    pub session: String,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct SeatOverride {
+    pub greeter: Option<GreeterKind>,
+    pub runtime: Option<Runtime>,
+    pub autologin: Option<Autologin>,
+    pub unit: Option<String>,
+}
+
+impl Config {
+    fn seat(&self, id: &SeatID) -> Option<&SeatOverride> {
+        self.seats.get(id.as_str())
+    }
+
+    pub fn greeter_for(&self, id: &SeatID) -> GreeterKind {
+        self.seat(id)
+            .and_then(|x| x.greeter)
+            .unwrap_or(self.greeter)
+    }
+
+    pub fn runtime_for(&self, id: &SeatID) -> Option<Runtime> {
+        self.seat(id)
+            .and_then(|x| x.runtime)
+            .or(self.sessions.runtime)
+    }
+
+    pub fn unit_for(&self, id: &SeatID) -> Option<&str> {
+        self.seat(id)
+            .and_then(|x| x.unit.as_deref())
+            .or(self.sessions.unit.as_deref())
+    }
+
+    pub fn autologin_for(&self, id: &SeatID) -> Option<&Autologin> {
+        self.seat(id)
+            .and_then(|x| x.autologin.as_ref())
+            .or(self.autologin.as_ref())
+    }
+
+    // The user config wins over the session definition,
+    // so an admin can i.e. keep a specific user light regardless of what they start
+    pub fn class_for(&self, username: &str, session: UserClassFlags) -> SessionClass {
+        let user = self.users.get(username).copied().unwrap_or_default();
+        SessionClass::user(session.overridden_by(user))
+    }
+
+    pub fn user_providers(&self) -> Vec<ProviderKind> {
+        match &self.backends.users {
+            Some(order) => order.clone(),
+            None => ProviderKind::ALL
+                .into_iter()
+                .filter(|x| x.compiled_in())
+                .collect(),
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        // Parse once untyped to find out the version, this also reports syntax errors
+        let raw: Table = toml::from_str(text)?;
+
+        let version = match raw.get("version") {
+            Some(Value::Integer(x)) => *x,
+            Some(_) => bail!("'version' must be an integer"),
+            None => {
+                warn!(
+                    "Configuration has no version, assuming 0. Set 'version = {CURRENT_VERSION}' to silence this"
+                );
+                0
+            }
+        };
+
+        ensure!(
+            (0..=CURRENT_VERSION).contains(&version),
+            "Unsupported configuration version {version}, the latest supported is {CURRENT_VERSION}"
+        );
+
+        // Every version has its own schema, parsed from the text directly,
+        // so that errors (e.g. for unknown keys) keep their line numbers
+        Ok(match version {
+            0 => toml::from_str::<v0::Config>(text)?.into(),
+            _ => toml::from_str(text)?,
+        })
+    }
+}
+
+// Schemas of older versions, each converts into the next one
+mod v0 {
+    use std::collections::HashMap;
+
+    use serde::Deserialize;
+
+    use super::{Autologin, Backends, SeatOverride, Sessions};
+    use crate::{core::UserClassFlags, greet::GreeterKind, seat::SeatBackend};
+
+    #[derive(Deserialize, Default)]
+    #[serde(default, deny_unknown_fields)]
+    pub struct Config {
+        pub version: i64,
+        // Moved to backends.seat in v1
+        pub seat_backend: Option<SeatBackend>,
+        pub backends: Backends,
+        pub greeter: GreeterKind,
+        pub sessions: Sessions,
+        pub autologin: Option<Autologin>,
+        pub seats: HashMap<String, SeatOverride>,
+        pub users: HashMap<String, UserClassFlags>,
+    }
+
+    impl From<Config> for super::Config {
+        fn from(old: Config) -> Self {
+            let mut backends = old.backends;
+            backends.seat = old.seat_backend.or(backends.seat);
+
+            Self {
+                version: 1,
+                backends,
+                greeter: old.greeter,
+                sessions: old.sessions,
+                autologin: old.autologin,
+                seats: old.seats,
+                users: old.users,
+            }
+        }
+    }
+}
+
+// Readers take a snapshot when they start something (a greeter, a session),
+// so a reload only affects what is started afterwards
+pub static CONFIG: ResourceCell<Config> = ResourceCell::new();
+
+impl Resource for Config {
+    type Definition = PathBuf;
+    type Error = anyhow::Error;
+
+    async fn load(definition: &Self::Definition) -> Result<Self> {
+        let text = match fs_err::read_to_string(definition) {
+            Err(e) if matches!(e.kind(), ErrorKind::NotFound) => return Ok(Config::default()),
+            other => other,
+        }?;
+
+        Self::parse(&text).with_context(|| format!("Invalid configuration in {definition:?}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(text: &str) -> String {
+        format!("{:#}", Config::parse(text).err().unwrap())
+    }
+
+    #[test]
+    fn migrates_v0() {
+        let config = Config::parse("seat_backend = \"seatd\"\ngreeter = \"tty\"\n").unwrap();
+
+        assert_eq!(config.version, CURRENT_VERSION);
+        assert!(config.backends.seat == Some(SeatBackend::Seatd));
+    }
+
+    #[test]
+    fn reports_unknown_keys_with_lines() {
+        let current = format!("version = {CURRENT_VERSION}\n\n[backends]\nseats = \"logind\"\n");
+        assert!(error(&current).contains("line 4"));
+
+        // Older versions have their own schema, and keep line numbers too
+        let v0 = "seat_backend = \"logind\"\n\n[sessions]\npath = []\n";
+        assert!(error(v0).contains("line 4"));
+    }
+
+    #[test]
+    fn users_override_session_classes() {
+        let config = Config::parse(&format!(
+            "version = {CURRENT_VERSION}\n\
+             [sessions.shell]\nlight = true\n\
+             [users.alice]\nearly = true\nlight = false\n"
+        ))
+        .unwrap();
+        let shell = config.sessions.shell;
+
+        let alice = SessionClass::User {
+            early: true,
+            light: false,
+        };
+        let bob = SessionClass::User {
+            early: false,
+            light: true,
+        };
+
+        assert_eq!(config.class_for("alice", shell), alice);
+        assert_eq!(config.class_for("bob", shell), bob);
+    }
+
+    #[test]
+    fn seats_override_the_session_unit() {
+        let config = Config::parse(&format!(
+            "version = {CURRENT_VERSION}\n\
+             [sessions]\nunit = \"shell.service\"\n\
+             [seats.seat1]\nunit = \"kiosk.service\"\n"
+        ))
+        .unwrap();
+
+        assert_eq!(config.unit_for(&SeatID::default()), Some("shell.service"));
+        assert_eq!(
+            config.unit_for(&SeatID("seat1".to_string())),
+            Some("kiosk.service")
+        );
+        assert_eq!(Config::default().unit_for(&SeatID::default()), None);
+    }
+
+    #[test]
+    fn rejects_future_versions() {
+        let text = format!("version = {}\n", CURRENT_VERSION + 1);
+        assert!(error(&text).contains("Unsupported configuration version"));
+    }
+
+    #[test]
+    fn v1_has_no_top_level_seat_backend() {
+        let text = format!("version = {CURRENT_VERSION}\nseat_backend = \"logind\"\n");
+        assert!(error(&text).contains("line 2"));
+    }
+}
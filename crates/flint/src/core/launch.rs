@@ -0,0 +1,647 @@
+use std::{
+    ffi::{CString, OsStr, OsString},
+    fmt::Display,
+    io,
+    os::{fd::OwnedFd, unix::process::ExitStatusExt},
+    process::ExitStatus,
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use rustix::{
+    process::{self, Gid, Pid, Signal, Uid},
+    thread,
+};
+use tokio::process::{Child, Command};
+
+use super::{
+    ResolvedTarget, Runtime,
+    lock::{self, LockOutcome},
+    new_shell_session, os_error,
+    pam::PamSession,
+    systemd::{self, UnitState},
+};
+use crate::{
+    seat::{SeatID, view::View},
+    user::UserMeta,
+    utils::{
+        tty::{Lent, Terminal},
+        warn::WarnExt,
+    },
+};
+
+const UNIT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// Everything the child needs to become the user.
+// Resolved before forking, as the child must not allocate or call into NSS.
+#[derive(Clone)]
+struct Credentials {
+    uid: Uid,
+    gid: Gid,
+    groups: Vec<Gid>,
+}
+
+impl Credentials {
+    fn resolve(username: &str, user: &UserMeta) -> Result<Self> {
+        let name = CString::new(username).context("Username contains a NUL byte")?;
+
+        let groups = flint_pam::getgrouplist(&name, user.gid)
+            .into_iter()
+            .map(Gid::from_raw)
+            .collect();
+
+        Ok(Self {
+            uid: Uid::from_raw(user.uid),
+            gid: Gid::from_raw(user.gid),
+            groups,
+        })
+    }
+
+    // Runs in the child.
+    // These are raw syscalls which only affect the calling thread,
+    // which is fine, as a forked child only has one.
+    fn apply(&self) -> io::Result<()> {
+        // Nothing to drop, i.e. flint is not running as root
+        if process::geteuid() == self.uid {
+            return Ok(());
+        }
+
+        thread::set_thread_groups(&self.groups).map_err(os_error)?;
+        thread::set_thread_gid(self.gid).map_err(os_error)?;
+        thread::set_thread_uid(self.uid).map_err(os_error)?;
+        Ok(())
+    }
+}
+
+fn set_env(env: &mut Vec<(OsString, OsString)>, key: OsString, value: OsString) {
+    env.retain(|(k, _)| *k != key);
+    env.push((key, value));
+}
+
+// How to run processes as the user of an open PAM session
+#[derive(Clone)]
+pub struct UserContext {
+    username: String,
+    credentials: Credentials,
+    // Passed to chdir in the child, which cannot allocate a path
+    home: CString,
+    env: Vec<(OsString, OsString)>,
+    // Keys set by PAM, these are never overridden
+    pam_keys: Vec<OsString>,
+}
+
+impl UserContext {
+    pub fn new(pam: &PamSession, user: &UserMeta) -> Result<Self> {
+        Self::with_env(pam.username(), user, pam.env_list())
+    }
+
+    // The environment as if PAM had set pam_env, see new
+    pub(super) fn with_env(
+        username: &str,
+        user: &UserMeta,
+        pam_env: Vec<(OsString, OsString)>,
+    ) -> Result<Self> {
+        let username = username.to_string();
+
+        // PAM has the final say over the environment
+        let mut env: Vec<(OsString, OsString)> = [
+            ("HOME", user.home.as_str()),
+            ("SHELL", user.shell.as_str()),
+            ("USER", username.as_str()),
+            ("LOGNAME", username.as_str()),
+            ("PATH", "/usr/local/bin:/usr/bin:/bin"),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.into(), v.into()))
+        .collect();
+
+        let mut pam_keys = Vec::new();
+        for (key, value) in pam_env {
+            pam_keys.push(key.clone());
+            set_env(&mut env, key, value);
+        }
+
+        Ok(Self {
+            credentials: Credentials::resolve(&username, user)?,
+            username,
+            home: CString::new(user.home.as_str()).context("Home directory contains a NUL byte")?,
+            env,
+            pam_keys,
+        })
+    }
+
+    // Precedence, lowest to highest: flint defaults, the user manager, PAM.
+    // PAM wins as it describes this specific session (seat, vt, session id),
+    // while the user manager environment is shared by all sessions of the user.
+    pub fn import_env(&mut self, imported: Vec<(OsString, OsString)>) {
+        for (key, value) in imported {
+            if !self.pam_keys.contains(&key) {
+                set_env(&mut self.env, key, value);
+            }
+        }
+    }
+
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
+    pub fn is_graphical(&self) -> bool {
+        self.env_var("XDG_SESSION_TYPE")
+            .is_some_and(|x| x == "wayland" || x == "x11")
+    }
+
+    pub fn pam_keys(&self) -> &[OsString] {
+        &self.pam_keys
+    }
+
+    pub fn env(&self) -> &[(OsString, OsString)] {
+        &self.env
+    }
+
+    pub fn env_var(&self, key: &str) -> Option<&OsStr> {
+        self.env
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_os_str())
+    }
+
+    // A command that forks into a new process-tree session (with the terminal as ctty),
+    // becomes the user and changes into the home directory before exec
+    pub fn command(&self, program: impl AsRef<OsStr>, tty: Option<Terminal<OwnedFd>>) -> Command {
+        let mut command = Command::new(program);
+        command.env_clear().envs(self.env.iter().cloned());
+
+        let credentials = self.credentials.clone();
+        let home = self.home.clone();
+
+        // Safety: the closure only makes syscalls on values prepared before the fork,
+        // and its errors are raw OS errors, so nothing allocates
+        unsafe {
+            command.pre_exec(move || {
+                match &tty {
+                    Some(tty) => {
+                        new_shell_session(tty)?;
+                        tty.set_as_stdio().map_err(os_error)?;
+                    }
+                    None => {
+                        process::setsid().map_err(os_error)?;
+                    }
+                }
+
+                credentials.apply()?;
+
+                // Like login(1), fall back to / if home is not accessible
+                process::chdir(home.as_c_str())
+                    .or_else(|_| process::chdir(c"/"))
+                    .map_err(os_error)?;
+                Ok(())
+            });
+        }
+
+        command
+    }
+}
+
+// A unit session which ended in the failed state, as opposed to flint failing to follow it
+#[derive(Debug)]
+pub struct UnitFailed {
+    pub unit: String,
+    // As systemd puts it, i.e. exit-code or timeout
+    pub result: String,
+}
+
+impl Display for UnitFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Session {} failed ({})", self.unit, self.result)
+    }
+}
+
+impl std::error::Error for UnitFailed {}
+
+pub struct RunningSession {
+    // Unset if the session is a unit we only observe
+    child: Option<Child>,
+    // Set if the session runs as a unit in the user manager
+    unit: Option<String>,
+    // Set if we activated the graphical session shim for the session
+    shim: bool,
+    // Where the session is shown, unset if PAM did not tell us
+    view: Option<View>,
+    // Set if the user owns the tty while the session runs, it goes back to root with the session
+    tty: Option<Lent<OwnedFd>>,
+    cx: UserContext,
+}
+
+impl RunningSession {
+    pub fn id(&self) -> Option<u32> {
+        self.child.as_ref().and_then(Child::id)
+    }
+
+    // The user manager does not tell us when a unit stops, so we ask
+    async fn wait_unit(&self, unit: &str) -> Result<ExitStatus> {
+        loop {
+            tokio::time::sleep(UNIT_POLL_INTERVAL).await;
+
+            let state = systemd::unit_state(&self.cx, unit)
+                .await
+                .with_context(|| format!("Failed to follow session unit {unit}"))?;
+
+            match state {
+                UnitState::Up => continue,
+                UnitState::Inactive => return Ok(ExitStatus::from_raw(0)),
+                UnitState::Failed => break,
+            }
+        }
+
+        // Only for the user to see, so failing to get it is not a reason to fail differently
+        let result = systemd::unit_result(&self.cx, unit)
+            .await
+            .context("Failed to get the result of the session unit")
+            .warn()
+            .unwrap_or_else(|| "unknown".to_string());
+
+        Err(UnitFailed {
+            unit: unit.to_string(),
+            result,
+        }
+        .into())
+    }
+
+    // Resolves once the session is over.
+    // For transient units, the child mirrors the unit state, and exits with its result.
+    pub async fn wait(&mut self) -> Result<ExitStatus> {
+        let status = if let Some(child) = &mut self.child {
+            child
+                .wait()
+                .await
+                .context("Failed to wait for the session process")?
+        } else if let Some(unit) = &self.unit {
+            self.wait_unit(unit).await?
+        } else {
+            unreachable!("a session is either a process or a unit")
+        };
+
+        if self.shim {
+            systemd::stop_graphical_shim(&self.cx)
+                .await
+                .context("Failed to stop graphical session shim")
+                .warn();
+        }
+
+        Ok(status)
+    }
+
+    // Resolves once the session is unlocked, or another user wants the seat.
+    // Does not borrow the session, so that it can be waited for meanwhile.
+    pub fn lock(&self, seat: &SeatID) -> impl Future<Output = Result<LockOutcome>> + use<> {
+        let seat = seat.clone();
+        let username = self.cx.username().to_string();
+        let view = self.view.clone();
+
+        async move {
+            let view = view.context("Cannot lock a session without a view")?;
+            lock::lock(&seat, &username, &view).await
+        }
+    }
+
+    pub async fn stop(&mut self) -> Result<()> {
+        if let Some(unit) = &self.unit {
+            return systemd::stop_unit(&self.cx, unit).await;
+        }
+
+        let Some(pid) = self.id() else {
+            // Already exited
+            return Ok(());
+        };
+
+        // The session leads its own process group
+        let pid = Pid::from_raw(pid as i32).context("Invalid session pid")?;
+        process::kill_process_group(pid, Signal::TERM).context("Failed to stop session")?;
+        Ok(())
+    }
+}
+
+// The user is resolved by the caller, by the name PAM settled on
+pub async fn launch(
+    pam: &PamSession,
+    user: &UserMeta,
+    target: &ResolvedTarget,
+    tty: Option<Terminal<OwnedFd>>,
+) -> Result<RunningSession> {
+    let cx = UserContext::new(pam, user)?;
+
+    // Only needed to lock the session later
+    let view = pam.view().ok();
+
+    launch_as(cx, view, user, target, tty).await
+}
+
+async fn launch_as(
+    mut cx: UserContext,
+    view: Option<View>,
+    user: &UserMeta,
+    target: &ResolvedTarget,
+    tty: Option<Terminal<OwnedFd>>,
+) -> Result<RunningSession> {
+    let mut shim = false;
+    let mut lent = None;
+
+    let (mut command, unit) = match target {
+        ResolvedTarget::Command {
+            executable,
+            runtime: Runtime::Unix,
+        } => (cx.command(executable, tty), None),
+
+        ResolvedTarget::Command {
+            executable,
+            runtime: Runtime::Systemd,
+        } => {
+            // The unit opens the tty by path, as the user
+            lent = tty
+                .map(|x| x.lend(user.uid, user.gid))
+                .transpose()
+                .context("Failed to hand the tty over to the user")?;
+
+            let name = systemd::session_unit_name(&cx);
+            let number = lent.as_ref().map(|x| x.number);
+            let command = systemd::transient_unit(&cx, &name, executable, number);
+            (command, Some(format!("{name}.service")))
+        }
+
+        ResolvedTarget::Command {
+            executable,
+            runtime: Runtime::Hybrid,
+        } => {
+            let env = systemd::manager_environment(&cx)
+                .await
+                .context("Failed to get the environment of the user manager")?;
+            cx.import_env(env);
+
+            shim = cx.is_graphical();
+            (cx.command(executable, tty), None)
+        }
+
+        ResolvedTarget::Command {
+            executable,
+            runtime: Runtime::Split,
+        } => {
+            systemd::start_user_manager(user.uid)
+                .await
+                .context("Failed to start the user manager")?;
+
+            // Services started later should know about the session, not the other way around
+            let keys: Vec<&str> = cx.pam_keys().iter().filter_map(|x| x.to_str()).collect();
+            systemd::export_env(&cx, &keys)
+                .await
+                .context("Failed to pass the session environment to the user manager")
+                .warn();
+
+            (cx.command(executable, tty), None)
+        }
+
+        // Nothing to spawn, the user manager runs the session
+        ResolvedTarget::Unit { name } => {
+            systemd::start_unit(&cx, name)
+                .await
+                .with_context(|| format!("Could not start session {name}"))?;
+
+            return Ok(RunningSession {
+                child: None,
+                unit: Some(name.clone()),
+                shim: false,
+                view,
+                tty: None,
+                cx,
+            });
+        }
+    };
+
+    let child = command
+        .spawn()
+        .with_context(|| format!("Failed to start session {target}"))?;
+
+    // Desktops that expect to run under systemd wait for graphical-session.target
+    if shim {
+        shim = systemd::start_graphical_shim(&cx)
+            .await
+            .context("Failed to start graphical session shim")
+            .warn()
+            .is_some();
+    }
+
+    Ok(RunningSession {
+        child: Some(child),
+        unit,
+        shim,
+        view,
+        tty: lent,
+        cx,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        os::unix::fs::PermissionsExt,
+        path::{Path, PathBuf},
+    };
+
+    use super::*;
+    use crate::core::systemd::tests::Stubs;
+
+    // nobody if the tests run as root, so that privileges are actually dropped.
+    // Otherwise there is nothing to drop, and sessions run as whoever runs the tests.
+    fn unprivileged(home: &Path) -> (String, UserMeta) {
+        let home = home.to_str().unwrap().to_string();
+        let shell = "/bin/sh".to_string();
+
+        match process::geteuid().is_root() {
+            true => {
+                let user = UserMeta {
+                    uid: 65534,
+                    gid: 65534,
+                    home,
+                    shell,
+                };
+                ("nobody".to_string(), user)
+            }
+            false => {
+                let user = UserMeta {
+                    uid: process::geteuid().as_raw(),
+                    gid: process::getegid().as_raw(),
+                    home,
+                    shell,
+                };
+                ("flint-test".to_string(), user)
+            }
+        }
+    }
+
+    // Readable by anyone, as the session may not run as the owner
+    fn shared_dir() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        fs::set_permissions(dir.path(), fs::Permissions::from_mode(0o755)).unwrap();
+        dir
+    }
+
+    fn script(dir: &Path, body: &str) -> PathBuf {
+        let path = dir.join("session");
+        fs::write(&path, format!("#!/bin/sh\n{body}\n")).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        path
+    }
+
+    async fn run(executable: &Path, home: &Path, env: &[(&str, &str)]) -> ExitStatus {
+        let (username, user) = unprivileged(home);
+
+        let env = env.iter().map(|(k, v)| (k.into(), v.into())).collect();
+        let cx = UserContext::with_env(&username, &user, env).unwrap();
+
+        let target = ResolvedTarget::Command {
+            executable: executable.to_owned(),
+            runtime: Runtime::Unix,
+        };
+
+        let mut session = launch_as(cx, None, &user, &target, None).await.unwrap();
+        session.wait().await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn reports_exit_status() {
+        let home = shared_dir();
+
+        let status = run(Path::new("/bin/true"), home.path(), &[]).await;
+        assert!(status.success());
+
+        let status = run(Path::new("/bin/false"), home.path(), &[]).await;
+        assert_eq!(status.code(), Some(1));
+    }
+
+    #[tokio::test]
+    async fn runs_as_the_user_in_home() {
+        let dir = shared_dir();
+        let (_, user) = unprivileged(dir.path());
+
+        let session = script(
+            dir.path(),
+            r#"[ "$(id -u)" = "$EXPECTED_UID" ] && [ "$(id -g)" = "$EXPECTED_GID" ] && [ "$(pwd)" = "$HOME" ]"#,
+        );
+
+        let uid = user.uid.to_string();
+        let gid = user.gid.to_string();
+        let env = [
+            ("EXPECTED_UID", uid.as_str()),
+            ("EXPECTED_GID", gid.as_str()),
+        ];
+
+        assert!(run(&session, dir.path(), &env).await.success());
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_root_without_home() {
+        let dir = shared_dir();
+        let session = script(dir.path(), r#"[ "$(pwd)" = / ]"#);
+
+        let missing = dir.path().join("missing");
+        assert!(run(&session, &missing, &[]).await.success());
+    }
+
+    #[tokio::test]
+    async fn leads_its_own_session() {
+        let dir = shared_dir();
+        // The 6th field of stat is the session id, which is the pid of the session leader
+        let session = script(dir.path(), r#"[ "$(cut -d' ' -f6 /proc/$$/stat)" = "$$" ]"#);
+
+        assert!(run(&session, dir.path(), &[]).await.success());
+    }
+
+    // Unit sessions are followed through systemctl, each call takes the next queued line:
+    // the start, then the states of the unit, and finally its result if it failed
+    async fn run_unit(outputs: &[&str]) -> Result<ExitStatus> {
+        let stubs = Stubs::new();
+        stubs.queue("systemctl", outputs);
+
+        let target = ResolvedTarget::Unit {
+            name: "kiosk.service".to_string(),
+        };
+
+        let mut session = launch_as(stubs.cx(&[]), None, &stubs.user(), &target, None)
+            .await
+            .unwrap();
+        session.wait().await
+    }
+
+    #[tokio::test]
+    async fn unit_sessions_end_when_the_unit_stops() {
+        let status = run_unit(&["", "active", "inactive"]).await.unwrap();
+        assert!(status.success());
+    }
+
+    #[tokio::test]
+    async fn unit_sessions_fail_with_the_unit() {
+        let error = run_unit(&["", "active", "failed", "exit-code"])
+            .await
+            .unwrap_err();
+        let failed = error.downcast_ref::<UnitFailed>().unwrap();
+
+        assert_eq!(failed.unit, "kiosk.service");
+        assert_eq!(failed.result, "exit-code");
+    }
+
+    // How the environment of a session differs between runtimes, see Runtime
+
+    fn env_cx(pam_env: &[(&str, &str)]) -> UserContext {
+        let (username, user) = unprivileged(Path::new("/home/flint"));
+        let pam_env = pam_env.iter().map(|(k, v)| (k.into(), v.into())).collect();
+        UserContext::with_env(&username, &user, pam_env).unwrap()
+    }
+
+    fn var<'a>(cx: &'a UserContext, key: &str) -> Option<&'a str> {
+        cx.env_var(key).map(|x| x.to_str().unwrap())
+    }
+
+    // Unix, Split and Systemd: flint defaults, then PAM
+    #[test]
+    fn pam_overrides_defaults() {
+        let cx = env_cx(&[("PATH", "/pam/bin"), ("XDG_SEAT", "seat0")]);
+
+        assert_eq!(var(&cx, "PATH"), Some("/pam/bin"));
+        assert_eq!(var(&cx, "HOME"), Some("/home/flint"));
+        assert_eq!(var(&cx, "XDG_SEAT"), Some("seat0"));
+
+        // Set once, even if PAM overrides a default
+        assert_eq!(cx.env().iter().filter(|(k, _)| k == "PATH").count(), 1);
+    }
+
+    // Hybrid: the user manager goes in between
+    #[test]
+    fn hybrid_imports_below_pam() {
+        let mut cx = env_cx(&[("XDG_SEAT", "seat0")]);
+        cx.import_env(vec![
+            ("XDG_SEAT".into(), "seat1".into()),
+            ("PATH".into(), "/manager/bin".into()),
+            ("EDITOR".into(), "vi".into()),
+        ]);
+
+        // PAM describes this session, the manager is shared by all sessions of the user
+        assert_eq!(var(&cx, "XDG_SEAT"), Some("seat0"));
+        assert_eq!(var(&cx, "PATH"), Some("/manager/bin"));
+        assert_eq!(var(&cx, "EDITOR"), Some("vi"));
+    }
+
+    // Split: nothing comes back from the user manager,
+    // only the variables set by PAM are pushed into it, never flint defaults
+    #[test]
+    fn split_exports_only_pam_keys() {
+        let cx = env_cx(&[("XDG_SEAT", "seat0"), ("PATH", "/pam/bin")]);
+
+        let exported: Vec<&str> = cx.pam_keys().iter().map(|x| x.to_str().unwrap()).collect();
+        assert_eq!(exported, ["XDG_SEAT", "PATH"]);
+
+        // So user services keep the defaults of the manager,
+        // and the session never sees variables set in the manager, i.e. by a user service
+        assert!(!exported.contains(&"HOME"));
+        assert_eq!(var(&cx, "EDITOR"), None);
+    }
+}
@@ -0,0 +1,106 @@
+use anyhow::{Context, Result, bail};
+use flint_pam::{PamReturnCode, bridge};
+use tracing::{info, warn};
+
+use super::{SessionClass, pam};
+use crate::{
+    config::CONFIG,
+    greet::{self, GreeterObject},
+    seat::{SeatID, view::View},
+    utils::tty::{SwitchLock, VT},
+};
+
+// Typed on the lock screen instead of unlocking
+const SWITCH_USER: &str = "switch";
+
+pub enum LockOutcome {
+    // The owner authenticated, the session is shown again
+    Unlocked,
+    // Someone else wants to log in.
+    // The session stays locked in the background for as long as this is kept.
+    SwitchUser(SwitchLock),
+}
+
+enum Attempt {
+    Unlock,
+    SwitchUser,
+}
+
+async fn attempt(greeter: &mut GreeterObject, username: &str) -> Result<Attempt> {
+    let prompt = format!("Press enter to unlock, or type '{SWITCH_USER}' to switch user: ");
+    if greeter.prompt(prompt, true).await?.trim() == SWITCH_USER {
+        return Ok(Attempt::SwitchUser);
+    }
+
+    let username = username.to_string();
+    let (auth, conversation) =
+        bridge::spawn(move |display| pam::reauthenticate(&username, display));
+
+    // If the greeter fails, PAM sees the conversation drop and gives up
+    let conversed = greet::converse(greeter, conversation).await;
+
+    auth.await.context("Authentication task failed")??;
+    conversed?;
+    Ok(Attempt::Unlock)
+}
+
+// What to tell the user, as the raw PAM message is rarely helpful
+pub(super) fn failure_message(error: &anyhow::Error) -> &'static str {
+    let code = error
+        .downcast_ref::<flint_pam::Error>()
+        .and_then(flint_pam::Error::code);
+
+    match code {
+        Some(PamReturnCode::NEW_AUTHTOK_REQD) => "Your password has expired",
+        Some(PamReturnCode::ACCT_EXPIRED) => "Your account has expired",
+        Some(PamReturnCode::MAXTRIES) => "Too many failed attempts",
+        _ => "Authentication failed",
+    }
+}
+
+// Shows a lock screen greeter until the owner of the session authenticates again.
+// The session may have put its vt into graphics mode, so the lock screen gets a vt of its own.
+pub async fn lock(seat: &SeatID, username: &str, view: &View) -> Result<LockOutcome> {
+    let View::Vt(session_vt) = view else {
+        bail!("Only sessions on a vt can be locked");
+    };
+
+    // Otherwise Ctrl+Alt+Fn would lead straight back to the session
+    let switching = SwitchLock::acquire()?;
+
+    let kind = CONFIG.get().greeter_for(seat);
+    let lock_view = View::Vt(VT::first_free()?);
+
+    let mut greeter = greet::greeter(kind, &lock_view, SessionClass::LockScreen)
+        .await
+        .context("Failed to start lock screen")?;
+
+    info!("Session of {username} on seat {} is locked", seat.as_str());
+
+    loop {
+        match attempt(&mut greeter, username).await {
+            Ok(Attempt::Unlock) => break,
+            Ok(Attempt::SwitchUser) => {
+                info!("Switching user on seat {}", seat.as_str());
+                return Ok(LockOutcome::SwitchUser(switching));
+            }
+            Err(e) => {
+                warn!("Failed to unlock session of {username}: {e:?}");
+                greeter.display(failure_message(&e).to_string()).await?;
+            }
+        }
+    }
+
+    drop(greeter);
+
+    VT::open(*session_vt)?
+        .activate()
+        .context("Failed to switch back to the session")?;
+    drop(switching);
+
+    info!(
+        "Session of {username} on seat {} is unlocked",
+        seat.as_str()
+    );
+    Ok(LockOutcome::Unlocked)
+}
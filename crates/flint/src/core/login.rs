@@ -0,0 +1,211 @@
+use std::process::ExitStatus;
+
+use anyhow::{Context, Result, bail};
+use envy::{EnvVariable, parse::EnvironmentParse};
+use flint_pam::bridge;
+use tracing::{info, warn};
+
+use super::{
+    Target,
+    launch::{self, RunningSession, UnitFailed},
+    lock::{self, LockOutcome},
+    pam::PamSession,
+};
+use crate::{
+    SeatContext, SessionSlot,
+    config::CONFIG,
+    driver::SessionTypeEnv,
+    greet::{self, GreeterObject},
+    seat::{SeatID, view::View},
+    user::UserProviders,
+    utils::tty::{SwitchLock, VT, VtNumber},
+};
+
+// A running user session, along with the PAM session it belongs to
+pub struct LoggedIn {
+    // Fields drop in order, so the session is gone before PAM closes
+    session: RunningSession,
+    pam: PamSession,
+}
+
+impl LoggedIn {
+    pub fn username(&self) -> &str {
+        self.pam.username()
+    }
+
+    // Resolves once the session is over, locking it whenever that is requested.
+    // If the seat goes away first, the session is stopped instead.
+    pub async fn run(mut self, seat: &SeatContext, slot: SessionSlot) -> Result<ExitStatus> {
+        let mut lock_requests = seat.lock_requests();
+
+        // Kept while another user is logged in, so that the session stays locked
+        // until its lock screen is back
+        let mut switched: Option<SwitchLock> = None;
+
+        loop {
+            if switched.is_none() {
+                tokio::select! {
+                    status = self.session.wait() => return status,
+                    _ = slot.shutdown.cancelled() => break,
+                    Ok(()) = lock_requests.recv() => (),
+                }
+            }
+
+            let lock = self.session.lock(&seat.id);
+            let outcome = tokio::select! {
+                status = self.session.wait() => return status,
+                _ = slot.shutdown.cancelled() => break,
+                outcome = lock => outcome,
+            };
+            switched = None;
+
+            // Requests that came in while locked are stale
+            lock_requests = lock_requests.resubscribe();
+
+            match outcome {
+                Ok(LockOutcome::Unlocked) => (),
+                Ok(LockOutcome::SwitchUser(locked)) => {
+                    // The other user gets a greeter on a vt of their own
+                    let mut other = seat.shutdown().spawn(greet::switch_user(seat.clone()));
+
+                    tokio::select! {
+                        status = self.session.wait() => return status,
+                        _ = slot.shutdown.cancelled() => break,
+                        _ = &mut other => (),
+                    }
+
+                    switched = Some(locked);
+                }
+                Err(e) => warn!("Failed to lock session of {}: {e:?}", self.username()),
+            }
+        }
+
+        info!("Stopping session of {}", self.username());
+        self.session.stop().await?;
+        self.session.wait().await
+    }
+}
+
+// What pam_systemd needs to know to register the session
+fn session_env(seat: &SeatID, view: &View) -> Vec<(&'static str, String)> {
+    let mut env = vec![
+        (SeatID::KEY, seat.as_str().to_string()),
+        // Greeters only start text sessions for now
+        (SessionTypeEnv::KEY, "tty".to_string()),
+    ];
+
+    if let View::Vt(number) = view {
+        env.push((VtNumber::KEY, number.env_serialize()));
+    }
+
+    env
+}
+
+// Authenticates whoever is at the greeter, then starts their session
+pub async fn login(seat: &SeatID, view: &View, greeter: &mut GreeterObject) -> Result<LoggedIn> {
+    let env = session_env(seat, view);
+
+    // PAM also prompts for the username
+    let (auth, conversation) = bridge::spawn(move |display| {
+        let config = CONFIG.get();
+        let class = |username: &str| config.class_for(username, config.sessions.shell);
+        PamSession::start(&env, class, None, Some(display), true)
+    });
+
+    // If the greeter fails, PAM sees the conversation drop and gives up
+    let conversed = greet::converse(greeter, conversation).await;
+
+    let pam = auth.await.context("Authentication task failed")??;
+    conversed?;
+
+    let providers = CONFIG.get().user_providers();
+    let user = UserProviders::connect(&providers)
+        .await
+        .resolve(pam.username())
+        .await?;
+
+    // Like login(1), a text session is the login shell of the user,
+    // unless the seat is set up to start a unit instead
+    let target = {
+        let config = CONFIG.get();
+        match config.unit_for(seat) {
+            Some(name) => Target::Unit {
+                name: name.to_string(),
+            },
+            None => Target::Command {
+                executable: user.shell.clone().into(),
+                runtime: None,
+            },
+        }
+        .resolve(seat, &config)
+    };
+
+    let tty = match view {
+        View::Vt(number) => VT::open(*number)?
+            .try_clone()
+            .context("Failed to duplicate the session vt")?,
+        View::Seat(_) => bail!("Text sessions can only run on a vt"),
+    };
+
+    info!(
+        "Starting {target} for {} on seat {}",
+        pam.username(),
+        seat.as_str()
+    );
+    let session = launch::launch(&pam, &user, &target, Some(tty)).await?;
+
+    Ok(LoggedIn { session, pam })
+}
+
+// What to tell the user about how their session ended, once the greeter is back
+pub fn end_notice(ended: &Result<ExitStatus>) -> String {
+    match ended {
+        Ok(status) if status.success() => "Session ended".to_string(),
+        Ok(status) => format!("Session ended with {status}"),
+        Err(e) => match e.downcast_ref::<UnitFailed>() {
+            Some(failed) => failed.to_string(),
+            None => format!("Session ended unexpectedly: {e}"),
+        },
+    }
+}
+
+// What to tell the user about a failed login, once the greeter is back
+pub fn failure_notice(error: &anyhow::Error) -> String {
+    match error.downcast_ref::<flint_pam::Error>() {
+        Some(_) => lock::failure_message(error).to_string(),
+        None => format!("Could not start your session: {error}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::process::ExitStatusExt;
+
+    use super::*;
+
+    #[test]
+    fn tells_how_sessions_ended() {
+        let status = |raw| Ok(ExitStatus::from_raw(raw));
+
+        assert_eq!(end_notice(&status(0)), "Session ended");
+        assert_eq!(
+            end_notice(&status(1 << 8)),
+            "Session ended with exit status: 1"
+        );
+    }
+
+    #[test]
+    fn tells_why_units_failed() {
+        let failed = UnitFailed {
+            unit: "kiosk.service".to_string(),
+            result: "exit-code".to_string(),
+        };
+
+        // Context added on the way up does not hide the failure
+        let error = anyhow::Error::from(failed).context("Session ended");
+        assert_eq!(
+            end_notice(&Err(error)),
+            "Session kiosk.service failed (exit-code)"
+        );
+    }
+}
@@ -1,14 +1,23 @@
+mod launch;
+pub mod lock;
+pub mod login;
 mod pam;
+mod systemd;
 
-use std::{os::fd::AsFd, path::PathBuf};
+use std::{fmt::Display, io, os::fd::AsFd, path::PathBuf};
 
-use anyhow::{Context, Result};
+use anyhow::Result;
 use envy::{define_env, parse::EnvironmentParse};
 use serde::{Deserialize, Serialize};
 
-use crate::utils::tty::Terminal;
+use crate::{config::Config, seat::SeatID, utils::tty::Terminal};
 
-#[derive(Serialize, Deserialize)]
+// How the environment of a session differs between runtimes:
+// - Unix: flint defaults + PAM, the user manager is not involved
+// - Split: flint defaults + PAM, which are also pushed into the user manager
+// - Hybrid: flint defaults + user manager + PAM, pulled from the user manager at launch
+// - Systemd: flint defaults + PAM, set on the session unit only
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
 pub enum Runtime {
     // run like systemd does not exist
     Unix,
@@ -16,6 +25,7 @@ pub enum Runtime {
     // activate systemd, but do not integrate
     // mirrors behaviour of some other session managers
     // not recommended to use, except for compatibility
+    // the environment only flows from the session into the user manager
     Split,
 
     // pull environment from systemd, provide unit shims
@@ -33,30 +43,77 @@ pub enum Target {
     #[serde(untagged)]
     Command {
         executable: PathBuf,
-        runtime: Option<Runtime>, // TODO: default is configured externally
+        // If unset, resolved from the config, see Target::resolve
+        runtime: Option<Runtime>,
     },
 }
 
-// TODO: make Default of Option<> easily resolvable globally
-// ref: make Config into OnceCell
+impl Runtime {
+    // Used when neither the session nor the config specify a runtime
+    pub const fn compiled_default() -> Self {
+        match cfg!(feature = "systemd") {
+            true => Self::Systemd,
+            false => Self::Unix,
+        }
+    }
+}
+
+impl Target {
+    // Precedence, highest to lowest:
+    // the session itself, the seat config, the global config, the compiled-in default
+    pub fn resolve(&self, seat: &SeatID, config: &Config) -> ResolvedTarget {
+        match self {
+            Self::Unit { name } => ResolvedTarget::Unit { name: name.clone() },
+            Self::Command {
+                executable,
+                runtime,
+            } => ResolvedTarget::Command {
+                executable: executable.clone(),
+                runtime: runtime
+                    .or_else(|| config.runtime_for(seat))
+                    .unwrap_or(Runtime::compiled_default()),
+            },
+        }
+    }
+}
 
-// impl Target {
-//     fn activates_systemd(&self) -> bool {
-//         match self {
-//             Self::Unit { .. } => true,
-//             Self::Command { runtime, .. } => !matches!(runtime, Runtime::Unix),
-//         }
-//     }
+// A Target with all defaults applied
+pub enum ResolvedTarget {
+    Unit {
+        name: String,
+    },
+    Command {
+        executable: PathBuf,
+        runtime: Runtime,
+    },
+}
 
-//     fn pulls_env_from_systemd(&self) -> bool {
-//         match self {
-//             // there is no need to
-//             Self::Unit { .. } => false,
-//             // all others either do not care, or already have the env
-//             Self::Command { runtime, .. } => matches!(runtime, Runtime::Hybrid),
-//         }
-//     }
-// }
+impl ResolvedTarget {
+    pub fn activates_systemd(&self) -> bool {
+        match self {
+            Self::Unit { .. } => true,
+            Self::Command { runtime, .. } => !matches!(runtime, Runtime::Unix),
+        }
+    }
+
+    pub fn pulls_env_from_systemd(&self) -> bool {
+        match self {
+            // there is no need to
+            Self::Unit { .. } => false,
+            // all others either do not care, or already have the env
+            Self::Command { runtime, .. } => matches!(runtime, Runtime::Hybrid),
+        }
+    }
+}
+
+impl Display for ResolvedTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unit { name } => write!(f, "unit {name}"),
+            Self::Command { executable, .. } => write!(f, "{}", executable.display()),
+        }
+    }
+}
 
 enum Kind {
     Text,
@@ -68,18 +125,35 @@ struct Session {
     kind: Kind,
 }
 
-// TODO: we are mixing two username flows at the moment.
-// f1: we ask for username -> we resolve -> we pass to pam -> pam asks for pass
-// f2: pam asks for username + pass -> pam resolves -> we ask pam -> we double-resolve??
-
 // UserIncomplete, Manager, Background and None are not here as those aren't relevant
 #[allow(dead_code)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum SessionClass {
+    // early: the session may start before the system has fully booted
+    // light: the session does not keep a user manager running
     User { early: bool, light: bool },
     Greeter,
     LockScreen,
 }
 
+// How a user session should be classified, unset fields fall through to the next source
+#[derive(Serialize, Deserialize, Clone, Copy, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct UserClassFlags {
+    pub early: Option<bool>,
+    pub light: Option<bool>,
+}
+
+impl UserClassFlags {
+    // Fields set in other take precedence
+    pub fn overridden_by(self, other: Self) -> Self {
+        Self {
+            early: other.early.or(self.early),
+            light: other.light.or(self.light),
+        }
+    }
+}
+
 impl SessionClass {
     fn user_default() -> Self {
         Self::User {
@@ -87,12 +161,31 @@ impl SessionClass {
             light: false,
         }
     }
+
+    pub fn user(flags: UserClassFlags) -> Self {
+        Self::User {
+            early: flags.early.unwrap_or(false),
+            light: flags.light.unwrap_or(false),
+        }
+    }
 }
 
 define_env!(SessionClass = #custom "XDG_SESSION_CLASS");
 
+// Either not a session class, or one flint does not handle (see above)
+#[derive(Debug)]
+pub struct UnsupportedSessionClass(String);
+
+impl Display for UnsupportedSessionClass {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Unsupported session class: {}", self.0)
+    }
+}
+
+impl std::error::Error for UnsupportedSessionClass {}
+
 impl EnvironmentParse<String> for SessionClass {
-    type Error = std::convert::Infallible;
+    type Error = UnsupportedSessionClass;
 
     fn env_serialize(self) -> String {
         match self {
@@ -111,17 +204,161 @@ impl EnvironmentParse<String> for SessionClass {
         }
     }
 
-    fn env_deserialize(_value: String) -> Result<Self, Self::Error> {
-        todo!()
+    fn env_deserialize(value: String) -> Result<Self, Self::Error> {
+        let user = |early, light| Self::User { early, light };
+
+        Ok(match value.as_str() {
+            "user" => user(false, false),
+            "user-early" => user(true, false),
+            "user-light" => user(false, true),
+            "user-early-light" => user(true, true),
+            "greeter" => Self::Greeter,
+            "lock-screen" => Self::LockScreen,
+            _ => return Err(UnsupportedSessionClass(value)),
+        })
     }
 }
 
 // TODO: does pam do this for us?
-fn new_shell_session<F: AsFd>(ctty: &Terminal<F>) -> Result<()> {
-    rustix::process::setsid().context("Failed to create a new process-tree session (setsid)")?;
+// Runs in a forked child, which must not allocate, so errors stay plain errnos
+fn new_shell_session<F: AsFd>(ctty: &Terminal<F>) -> io::Result<()> {
+    rustix::process::setsid().map_err(os_error)?;
+    ctty.set_as_ctty().map_err(os_error)?;
+    Ok(())
+}
 
-    ctty.set_as_ctty()
-        .context("Failed to set controlling tty")?;
+// Unlike io::Error::other, this does not box anything
+fn os_error(errno: rustix::io::Errno) -> io::Error {
+    io::Error::from_raw_os_error(errno.raw_os_error())
+}
 
-    Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The domain is small enough to check every value
+    fn all_classes() -> Vec<SessionClass> {
+        let mut all = vec![SessionClass::Greeter, SessionClass::LockScreen];
+
+        for early in [false, true] {
+            for light in [false, true] {
+                all.push(SessionClass::User { early, light });
+            }
+        }
+
+        all
+    }
+
+    #[test]
+    fn round_trips() {
+        for class in all_classes() {
+            let raw = class.env_serialize();
+            assert_eq!(SessionClass::env_deserialize(raw).unwrap(), class);
+        }
+    }
+
+    #[test]
+    fn serializes_as_logind_expects() {
+        let raw: Vec<String> = all_classes()
+            .into_iter()
+            .map(SessionClass::env_serialize)
+            .collect();
+
+        let expected = [
+            "greeter",
+            "lock-screen",
+            "user",
+            "user-light",
+            "user-early",
+            "user-early-light",
+        ];
+        assert_eq!(raw, expected);
+    }
+
+    #[test]
+    fn refuses_classes_flint_does_not_handle() {
+        let unhandled = [
+            "manager",
+            "manager-early",
+            "background",
+            "background-light",
+            "user-incomplete",
+            "none",
+            // logind only knows this order
+            "user-light-early",
+            "User",
+            "",
+        ];
+
+        for raw in unhandled {
+            let error = SessionClass::env_deserialize(raw.to_string()).unwrap_err();
+            assert_eq!(
+                error.to_string(),
+                format!("Unsupported session class: {raw}")
+            );
+        }
+    }
+
+    #[test]
+    fn resolves_runtimes_by_precedence() {
+        use Runtime::*;
+
+        // The session, the seat config, the global config, then the expected runtime
+        let cases = [
+            (Some(Unix), Some(Split), Some(Hybrid), Unix),
+            (None, Some(Split), Some(Hybrid), Split),
+            (None, None, Some(Hybrid), Hybrid),
+            (None, None, None, Runtime::compiled_default()),
+            (Some(Systemd), None, Some(Hybrid), Systemd),
+            (Some(Hybrid), Some(Unix), None, Hybrid),
+        ];
+
+        let name = |runtime: Runtime| match runtime {
+            Unix => "Unix",
+            Split => "Split",
+            Hybrid => "Hybrid",
+            Systemd => "Systemd",
+        };
+
+        for (i, (session, seat, global, expected)) in cases.into_iter().enumerate() {
+            let mut text = format!("version = {}\n", crate::config::CURRENT_VERSION);
+            if let Some(x) = global {
+                text += &format!("[sessions]\nruntime = \"{}\"\n", name(x));
+            }
+            if let Some(x) = seat {
+                text += &format!("[seats.seat1]\nruntime = \"{}\"\n", name(x));
+            }
+            let config = Config::parse(&text).unwrap();
+
+            let target = Target::Command {
+                executable: "/bin/sh".into(),
+                runtime: session,
+            };
+            let ResolvedTarget::Command { runtime, .. } =
+                target.resolve(&SeatID("seat1".to_string()), &config)
+            else {
+                panic!("case {i}: commands resolve to commands");
+            };
+            assert!(
+                runtime == expected,
+                "case {i}: resolved to {}",
+                name(runtime)
+            );
+
+            // Seat overrides only apply to their own seat
+            if seat.is_some() && session.is_none() {
+                let ResolvedTarget::Command { runtime, .. } =
+                    target.resolve(&SeatID::seat0(), &config)
+                else {
+                    panic!("case {i}: commands resolve to commands");
+                };
+                let fallback = global.unwrap_or(Runtime::compiled_default());
+                assert!(
+                    runtime == fallback,
+                    "case {i}: seat0 resolved to {}",
+                    name(runtime)
+                );
+            }
+        }
+    }
 }
@@ -1,47 +1,272 @@
+use std::ffi::OsString;
+
 use anyhow::{Context, Result};
+use envy::{EnvVariable, parse::EnvironmentParse};
 use flint_pam::*;
+use tracing::info;
 
+use super::SessionClass;
 use crate::seat::view::View;
 
-struct PamSession {
+const SERVICE: &str = "flint";
+
+// What the session is opened with
+fn pam_env<'a>(env: &[(&'a str, String)], class: SessionClass) -> Vec<(&'a str, String)> {
+    let mut env = env.to_vec();
+    // pam_systemd registers the session with this class
+    env.push((SessionClass::KEY, class.env_serialize()));
+    env
+}
+
+pub struct PamSession {
     pam: Pam,
+    // As PAM knows it, which is not necessarily what was typed in
+    username: String,
 }
 
 impl PamSession {
-    fn start(
-        env: impl envy::Diff,
+    pub fn start(
+        // Describes the session to PAM modules, i.e. XDG_SEAT for pam_systemd
+        env: &[(&str, String)],
+        // Picked by the caller, as only it knows what is being started.
+        // Called with the canonical username.
+        class: impl FnOnce(&str) -> SessionClass,
+        // If None, PAM prompts for it
         username: Option<&str>,
-        display: Option<impl PamDisplay>,
+        display: Option<impl PamDisplay + Send + 'static>,
         require_auth: bool,
     ) -> Result<Self> {
-        let mut pam = Pam::new("flint", display, username)?;
+        let pam = Pam::new(SERVICE, display, username)?;
+        Self::open(pam, env, class, require_auth)
+    }
 
+    // Split from start, so that tests can bring their own PAM service
+    fn open(
+        mut pam: Pam,
+        env: &[(&str, String)],
+        class: impl FnOnce(&str) -> SessionClass,
+        require_auth: bool,
+    ) -> Result<Self> {
         if require_auth {
             pam.authenticate(false)?;
         }
-        pam.assert_account_is_valid(false)?;
+
+        // Modules may have prompted for the name, or rewritten it (i.e. case folding, aliases).
+        // This is the only name flint uses from here on.
+        let username = pam.get_username()?;
+        let class = class(&username);
+
+        validate_account(&mut pam, &username)?;
         pam.credentials(CredentialsOP::Establish)?;
 
-        pam.set_env(env)?;
+        for (key, value) in pam_env(env, class) {
+            pam.put_env(key, &value)?;
+        }
         pam.open_session()?;
 
-        Ok(Self { pam })
+        Ok(Self { pam, username })
+    }
+
+    pub fn username(&self) -> &str {
+        &self.username
     }
 
-    fn username(&mut self) -> Result<String> {
-        self.pam.get_username()
+    pub fn env_list(&self) -> Vec<(OsString, OsString)> {
+        self.pam.env_list()
     }
 
-    fn view(&self) -> Result<View> {
+    pub fn view(&self) -> Result<View> {
         // TODO: this should never be necessary under new model
         View::from_env(&self.pam)
             .context("Could not get seat/vt from PAM env. Check if systemd_pam is in the stack.")
     }
 }
 
+// Users with an expired password have to change it before they get in
+fn validate_account(pam: &mut Pam, username: &str) -> Result<()> {
+    match pam.assert_account_is_valid(false) {
+        Err(e) if e.code() == Some(PamReturnCode::NEW_AUTHTOK_REQD) => {
+            info!("Password of {username} has expired, asking for a new one");
+            pam.change_auth_token(true)
+                .context("Failed to change expired password")
+        }
+        other => Ok(other?),
+    }
+}
+
+// Checks that the user is who they claim to be, without opening a new session.
+// Used to unlock a session which is already open.
+pub fn reauthenticate(username: &str, display: impl PamDisplay + Send + 'static) -> Result<()> {
+    let mut pam = Pam::new(SERVICE, Some(display), Some(username))?;
+
+    pam.authenticate(false)?;
+    validate_account(&mut pam, username)?;
+
+    // Some credentials (i.e. kerberos tickets) may have expired while the session was locked
+    pam.credentials(CredentialsOP::Reinitialize)?;
+    Ok(())
+}
+
 impl Drop for PamSession {
     fn drop(&mut self) {
         self.pam.close_session().unwrap();
         self.pam.credentials(CredentialsOP::Delete).unwrap();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::RefCell,
+        sync::{
+            Arc,
+            atomic::{AtomicUsize, Ordering},
+        },
+    };
+
+    use flint_pam::stub::{PasswordDisplay, StubService};
+
+    use super::*;
+    use crate::{config::Config, core::UserClassFlags};
+
+    // Opens a session as the given user, returns it along with the name class() was called with
+    fn open(service: &StubService, username: &str) -> (PamSession, String) {
+        let display = PasswordDisplay(String::new());
+        let pam = service.start(Some(display), Some(username)).unwrap();
+
+        let seen = RefCell::new(String::new());
+        let class = |username: &str| {
+            *seen.borrow_mut() = username.to_string();
+            SessionClass::User {
+                early: false,
+                light: false,
+            }
+        };
+
+        let session = PamSession::open(pam, &[], class, true).unwrap();
+        (session, seen.into_inner())
+    }
+
+    #[test]
+    fn takes_the_username_from_pam() {
+        let service = StubService::new("");
+
+        let (session, seen) = open(&service, "alice");
+        assert_eq!(session.username(), "alice");
+        assert_eq!(seen, "alice");
+    }
+
+    #[test]
+    fn follows_case_folding() {
+        let service = StubService::new("lowercase");
+
+        let (session, seen) = open(&service, "Alice");
+        assert_eq!(session.username(), "alice");
+        assert_eq!(seen, "alice");
+    }
+
+    #[test]
+    fn follows_aliases() {
+        let service = StubService::new("lowercase alias=admin:alice");
+
+        // Folded first, then renamed
+        let (session, seen) = open(&service, "ADMIN");
+        assert_eq!(session.username(), "alice");
+        assert_eq!(seen, "alice");
+    }
+
+    #[test]
+    fn exports_the_class_of_the_canonical_user() {
+        let config = Config::parse("version = 1\n[users.alice]\nearly = true\n").unwrap();
+        let service = StubService::new("lowercase");
+
+        let pam = service
+            .start(None::<PasswordDisplay>, Some("Alice"))
+            .unwrap();
+        let class = |username: &str| config.class_for(username, UserClassFlags::default());
+        let session = PamSession::open(pam, &[], class, true).unwrap();
+
+        let env = session.env_list();
+        assert!(env.contains(&("XDG_SESSION_CLASS".into(), "user-early".into())));
+    }
+
+    #[test]
+    fn exports_the_session_class() {
+        let config = Config::parse("version = 1\n[users.alice]\nlight = true\n").unwrap();
+        let class = config.class_for("alice", UserClassFlags::default());
+
+        let env = pam_env(&[("XDG_SEAT", "seat0".to_string())], class);
+        assert_eq!(
+            env,
+            [
+                ("XDG_SEAT", "seat0".to_string()),
+                ("XDG_SESSION_CLASS", "user-light".to_string()),
+            ]
+        );
+    }
+
+    // Answers every hidden prompt with the new password, or refuses them without one.
+    // Counts the prompts, as the handle owns the display.
+    struct NewPassword(Option<String>, Arc<AtomicUsize>);
+
+    impl PamDisplay for NewPassword {
+        fn prompt(&self, _text: &str, show: bool) -> Result<String, ConversationError> {
+            self.1.fetch_add(1, Ordering::Relaxed);
+            match (show, &self.0) {
+                (false, Some(password)) => Ok(password.clone()),
+                _ => Err(ConversationError),
+            }
+        }
+
+        fn message(&self, _text: &str, _level: MessageLevel) -> Result<(), ConversationError> {
+            Ok(())
+        }
+    }
+
+    // Opens a session for a user whose password has expired, returns it along with the number of prompts
+    fn open_expired(answer: Option<&str>) -> (Result<PamSession>, usize) {
+        let service = StubService::new("expired newpassword=fresh");
+        let prompts = Arc::new(AtomicUsize::new(0));
+
+        let display = NewPassword(answer.map(String::from), prompts.clone());
+        let pam = service.start(Some(display), Some("alice")).unwrap();
+
+        let class = |_: &str| SessionClass::User {
+            early: false,
+            light: false,
+        };
+        let session = PamSession::open(pam, &[], class, true);
+        (session, prompts.load(Ordering::Relaxed))
+    }
+
+    #[test]
+    fn asks_for_a_new_password_when_expired() {
+        let (session, prompts) = open_expired(Some("fresh"));
+
+        assert!(session.is_ok());
+        // The new password, then again to confirm it
+        assert_eq!(prompts, 2);
+    }
+
+    #[test]
+    fn fails_when_the_password_change_fails() {
+        let (session, prompts) = open_expired(Some("stale"));
+
+        let Err(error) = session else {
+            panic!("the session opened with the wrong new password");
+        };
+        assert!(format!("{error:#}").contains("Failed to change expired password"));
+        assert_eq!(prompts, 2);
+    }
+
+    #[test]
+    fn fails_when_the_password_change_is_refused() {
+        let (session, prompts) = open_expired(None);
+
+        let Err(error) = session else {
+            panic!("the session opened without a new password");
+        };
+        assert!(format!("{error:#}").contains("Failed to change expired password"));
+        assert_eq!(prompts, 1);
+    }
+}
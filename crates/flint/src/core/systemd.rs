@@ -0,0 +1,508 @@
+// Integration with the user's service manager.
+//
+// NOTE: we drive it through systemd-run and systemctl, running as the user.
+// This needs no D-Bus connection, and systemd-run --wait already
+// mirrors the unit state: it exits once the unit is inactive, with the unit's result.
+
+use std::{ffi::OsString, os::unix::ffi::OsStringExt, path::Path};
+
+use anyhow::{Context, Result, bail, ensure};
+use tokio::process::Command;
+
+use super::launch::UserContext;
+use crate::user::Uid;
+
+const SYSTEMD_RUN: &str = "systemd-run";
+const SYSTEMCTL: &str = "systemctl";
+
+// Units live in session.slice, which is where the user manager
+// expects essential session services
+const SESSION_SLICE: &str = "session.slice";
+
+// graphical-session.target refuses manual start, so we start this instead.
+// It is shipped in dist/systemd/user and binds to graphical-session.target.
+pub const GRAPHICAL_SHIM: &str = "flint-graphical-session.target";
+
+// Named after the logind session if there is one, so the two are easy to match.
+// Without a suffix, as a session has both a .service and a .scope unit.
+pub fn session_unit_name(cx: &UserContext) -> String {
+    let id = match cx.env_var("XDG_SESSION_ID") {
+        Some(id) => id.to_string_lossy().into_owned(),
+        None => uuid::Uuid::now_v7().simple().to_string(),
+    };
+
+    format!("flint-session-{id}")
+}
+
+// Runs the session as {name}.service, on the given tty (by number) if any.
+//
+// The returned command is the client that waits for the unit, which flint keeps
+// for as long as the PAM session is open. It runs in {name}.scope,
+// and the service is bound to that scope, so it never outlives the PAM session.
+pub fn transient_unit(cx: &UserContext, name: &str, executable: &Path, tty: Option<u8>) -> Command {
+    let (service, scope) = (format!("{name}.service"), format!("{name}.scope"));
+    let mut command = cx.command(SYSTEMD_RUN, None);
+
+    command
+        .args(["--user", "--scope", "--collect", "--quiet"])
+        .arg(format!("--slice={SESSION_SLICE}"))
+        .arg(format!("--unit={scope}"))
+        .arg("--description=flint session client");
+
+    command
+        .arg("--")
+        .arg(SYSTEMD_RUN)
+        .args(["--user", "--wait", "--collect", "--quiet"])
+        .arg("--service-type=exec")
+        .arg(format!("--slice={SESSION_SLICE}"))
+        .arg(format!("--unit={service}"))
+        .arg("--description=flint session")
+        .arg(format!("--property=BindsTo={scope}"))
+        .arg(format!("--property=After={scope}"));
+
+    // The unit opens the tty itself, the client has no use for it
+    if let Some(number) = tty {
+        command
+            .arg(format!("--property=TTYPath=/dev/tty{number}"))
+            .arg("--property=StandardInput=tty")
+            .arg("--property=StandardOutput=tty")
+            .arg("--property=StandardError=tty")
+            .arg("--property=TTYReset=yes")
+            .arg("--property=TTYVHangup=yes");
+    }
+
+    // Units inherit the environment of the manager, not ours.
+    // Only names are passed, systemd-run takes the values from its own environment,
+    // as the command line is readable by anyone.
+    for (key, _) in cx.env() {
+        let mut arg = OsString::from("--setenv=");
+        arg.push(key);
+        command.arg(arg);
+    }
+
+    command.arg("--").arg(executable);
+    command
+}
+
+// Runs as flint, the user manager is a unit of the system manager
+pub async fn start_user_manager(uid: Uid) -> Result<()> {
+    let status = Command::new(SYSTEMCTL)
+        .args(["start", &format!("user@{uid}.service")])
+        .status()
+        .await
+        .context("Failed to run systemctl")?;
+
+    ensure!(
+        status.success(),
+        "systemctl start user@{uid}.service failed: {status}"
+    );
+    Ok(())
+}
+
+// import-environment reads the values from its own environment,
+// which is the session environment
+pub async fn export_env(cx: &UserContext, keys: &[&str]) -> Result<()> {
+    if keys.is_empty() {
+        return Ok(());
+    }
+
+    let args: Vec<&str> = ["import-environment"]
+        .into_iter()
+        .chain(keys.iter().copied())
+        .collect();
+    systemctl(cx, &args).await?;
+    Ok(())
+}
+
+pub async fn stop_unit(cx: &UserContext, unit: &str) -> Result<()> {
+    systemctl(cx, &["stop", "--no-block", unit]).await?;
+    Ok(())
+}
+
+async fn systemctl(cx: &UserContext, args: &[&str]) -> Result<Vec<u8>> {
+    let output = cx
+        .command(SYSTEMCTL, None)
+        .arg("--user")
+        .args(args)
+        .output()
+        .await
+        .context("Failed to run systemctl")?;
+
+    // systemctl explains what went wrong on stderr, that is what the user should see
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        bail!("{}", stderr.trim());
+    }
+
+    Ok(output.stdout)
+}
+
+pub async fn start_graphical_shim(cx: &UserContext) -> Result<()> {
+    systemctl(cx, &["start", "--no-block", GRAPHICAL_SHIM]).await?;
+    Ok(())
+}
+
+pub async fn stop_graphical_shim(cx: &UserContext) -> Result<()> {
+    systemctl(cx, &["stop", "--no-block", GRAPHICAL_SHIM]).await?;
+    Ok(())
+}
+
+// Blocks until the unit is started, or failed to
+pub async fn start_unit(cx: &UserContext, unit: &str) -> Result<()> {
+    systemctl(cx, &["start", unit]).await?;
+    Ok(())
+}
+
+pub enum UnitState {
+    // Active, or on its way there
+    Up,
+    Inactive,
+    Failed,
+}
+
+pub async fn unit_state(cx: &UserContext, unit: &str) -> Result<UnitState> {
+    let output = systemctl(cx, &["show", "--property=ActiveState", "--value", unit]).await?;
+
+    Ok(match String::from_utf8_lossy(&output).trim() {
+        "inactive" => UnitState::Inactive,
+        "failed" => UnitState::Failed,
+        // active, activating, deactivating, reloading, refreshing, maintenance
+        _ => UnitState::Up,
+    })
+}
+
+// Why a unit failed, i.e. exit-code, signal or timeout
+pub async fn unit_result(cx: &UserContext, unit: &str) -> Result<String> {
+    let output = systemctl(cx, &["show", "--property=Result", "--value", unit]).await?;
+    Ok(String::from_utf8_lossy(&output).trim().to_string())
+}
+
+pub async fn manager_environment(cx: &UserContext) -> Result<Vec<(OsString, OsString)>> {
+    let output = systemctl(cx, &["show-environment"]).await?;
+
+    Ok(output
+        .split(|b| *b == b'\n')
+        .filter_map(|line| {
+            let split = line.iter().position(|b| *b == b'=')?;
+            let (key, value) = (&line[..split], &line[split + 1..]);
+
+            Some((
+                OsString::from_vec(key.to_vec()),
+                OsString::from_vec(unescape(value)),
+            ))
+        })
+        .collect())
+}
+
+// show-environment prints values with special characters as $'...'
+fn unescape(value: &[u8]) -> Vec<u8> {
+    let Some(inner) = value.strip_prefix(b"$'").and_then(|x| x.strip_suffix(b"'")) else {
+        return value.to_vec();
+    };
+
+    let mut out = Vec::with_capacity(inner.len());
+    let mut bytes = inner.iter().copied();
+
+    while let Some(byte) = bytes.next() {
+        if byte != b'\\' {
+            out.push(byte);
+            continue;
+        }
+
+        match bytes.next() {
+            Some(b'n') => out.push(b'\n'),
+            Some(b't') => out.push(b'\t'),
+            Some(b'r') => out.push(b'\r'),
+            Some(b'x') => {
+                let hex: Vec<u8> = bytes.by_ref().take(2).collect();
+                let parsed = std::str::from_utf8(&hex)
+                    .ok()
+                    .and_then(|x| u8::from_str_radix(x, 16).ok());
+
+                match parsed {
+                    Some(x) => out.push(x),
+                    None => out.extend(b"\\x".iter().chain(&hex)),
+                }
+            }
+            // \\, \' and \" stand for themselves
+            Some(other) => out.push(other),
+            None => out.push(byte),
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use std::{fs, os::unix::fs::PermissionsExt};
+
+    use rustix::process;
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::user::UserMeta;
+
+    // Stand-ins for systemd-run and systemctl, found through PATH.
+    // They record their arguments and environment, print STUB_OUTPUT
+    // (or the next line queued for them, see queue), and fail with STUB_ERROR on stderr if it is set.
+    pub(crate) struct Stubs {
+        dir: TempDir,
+    }
+
+    impl Stubs {
+        pub(crate) fn new() -> Self {
+            let dir = tempfile::tempdir().unwrap();
+
+            for name in [SYSTEMD_RUN, SYSTEMCTL] {
+                let path = dir.path().join(name);
+                let script = format!(
+                    r#"#!/bin/sh
+printf '%s\n' "$@" > "$STUBS/{name}.args"
+env > "$STUBS/{name}.env"
+printf '%s' "$STUB_OUTPUT"
+if [ -f "$STUBS/{name}.queue" ]; then
+    head -n 1 "$STUBS/{name}.queue"
+    sed -i 1d "$STUBS/{name}.queue"
+fi
+if [ -n "$STUB_ERROR" ]; then
+    echo "$STUB_ERROR" >&2
+    exit 1
+fi
+"#
+                );
+
+                fs::write(&path, script).unwrap();
+                fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+            }
+
+            Self { dir }
+        }
+
+        // Whoever runs the tests, so there are no privileges to drop
+        pub(crate) fn user(&self) -> UserMeta {
+            UserMeta {
+                uid: process::geteuid().as_raw(),
+                gid: process::getegid().as_raw(),
+                home: self.dir.path().to_str().unwrap().to_string(),
+                shell: "/bin/sh".to_string(),
+            }
+        }
+
+        pub(crate) fn cx(&self, env: &[(&str, &str)]) -> UserContext {
+            let dir = self.dir.path().to_str().unwrap();
+            let user = self.user();
+
+            let path = format!("{dir}:/usr/bin:/bin");
+            let env = [("PATH", path.as_str()), ("STUBS", dir)]
+                .iter()
+                .chain(env)
+                .map(|(k, v)| (k.into(), v.into()))
+                .collect();
+
+            UserContext::with_env("flint-test", &user, env).unwrap()
+        }
+
+        // Each call prints the next of these lines, in order
+        pub(crate) fn queue(&self, name: &str, outputs: &[&str]) {
+            let path = self.dir.path().join(format!("{name}.queue"));
+            fs::write(path, outputs.join("\n")).unwrap();
+        }
+
+        fn read(&self, name: &str, kind: &str) -> Vec<String> {
+            let path = self.dir.path().join(format!("{name}.{kind}"));
+            let text = fs::read_to_string(path).unwrap();
+            text.lines().map(String::from).collect()
+        }
+
+        fn args(&self, name: &str) -> Vec<String> {
+            self.read(name, "args")
+        }
+
+        fn env(&self, name: &str) -> Vec<String> {
+            self.read(name, "env")
+        }
+    }
+
+    async fn run_transient_unit(stubs: &Stubs, cx: &UserContext, tty: Option<u8>) -> Vec<String> {
+        let status = transient_unit(cx, "flint-session-test", Path::new("/bin/session"), tty)
+            .status()
+            .await
+            .unwrap();
+        assert!(status.success());
+
+        stubs.args(SYSTEMD_RUN)
+    }
+
+    #[tokio::test]
+    async fn transient_unit_is_bound_to_the_client_scope() {
+        let stubs = Stubs::new();
+        let args = run_transient_unit(&stubs, &stubs.cx(&[]), None).await;
+
+        // The outer systemd-run only puts the waiting client into a scope
+        let split = args.iter().position(|x| x == "--").unwrap();
+        let (scope, service) = (&args[..split], &args[split + 1..]);
+
+        assert!(scope.contains(&"--scope".to_string()));
+        assert!(scope.contains(&"--unit=flint-session-test.scope".to_string()));
+
+        assert_eq!(service[0], SYSTEMD_RUN);
+        assert!(service.contains(&"--wait".to_string()));
+        assert!(service.contains(&"--unit=flint-session-test.service".to_string()));
+        assert!(service.contains(&"--property=BindsTo=flint-session-test.scope".to_string()));
+        assert_eq!(service.last().unwrap(), "/bin/session");
+    }
+
+    #[tokio::test]
+    async fn transient_unit_gets_the_tty() {
+        let stubs = Stubs::new();
+        let args = run_transient_unit(&stubs, &stubs.cx(&[]), Some(5)).await;
+
+        for property in [
+            "TTYPath=/dev/tty5",
+            "StandardInput=tty",
+            "StandardOutput=tty",
+        ] {
+            assert!(args.contains(&format!("--property={property}")));
+        }
+
+        let stubs = Stubs::new();
+        let args = run_transient_unit(&stubs, &stubs.cx(&[]), None).await;
+        assert!(!args.iter().any(|x| x.starts_with("--property=TTYPath")));
+    }
+
+    #[tokio::test]
+    async fn environment_stays_off_the_command_line() {
+        let stubs = Stubs::new();
+        let cx = stubs.cx(&[("SECRET", "hunter2")]);
+        let args = run_transient_unit(&stubs, &cx, None).await;
+
+        assert!(args.contains(&"--setenv=SECRET".to_string()));
+        assert!(!args.iter().any(|x| x.contains("hunter2")));
+
+        // systemd-run takes the values from here
+        assert!(
+            stubs
+                .env(SYSTEMD_RUN)
+                .contains(&"SECRET=hunter2".to_string())
+        );
+    }
+
+    async fn unit_state_for(output: &str) -> UnitState {
+        let stubs = Stubs::new();
+        let cx = stubs.cx(&[("STUB_OUTPUT", output)]);
+        let state = unit_state(&cx, "x.service").await.unwrap();
+
+        let expected = [
+            "--user",
+            "show",
+            "--property=ActiveState",
+            "--value",
+            "x.service",
+        ];
+        assert_eq!(stubs.args(SYSTEMCTL), expected);
+        state
+    }
+
+    #[tokio::test]
+    async fn reads_unit_state() {
+        assert!(matches!(
+            unit_state_for("inactive\n").await,
+            UnitState::Inactive
+        ));
+        assert!(matches!(
+            unit_state_for("failed\n").await,
+            UnitState::Failed
+        ));
+        assert!(matches!(
+            unit_state_for("activating\n").await,
+            UnitState::Up
+        ));
+        assert!(matches!(unit_state_for("active\n").await, UnitState::Up));
+    }
+
+    #[tokio::test]
+    async fn reads_unit_result() {
+        let stubs = Stubs::new();
+        let cx = stubs.cx(&[("STUB_OUTPUT", "exit-code\n")]);
+
+        assert_eq!(unit_result(&cx, "x.service").await.unwrap(), "exit-code");
+        let expected = [
+            "--user",
+            "show",
+            "--property=Result",
+            "--value",
+            "x.service",
+        ];
+        assert_eq!(stubs.args(SYSTEMCTL), expected);
+    }
+
+    #[tokio::test]
+    async fn stops_units_without_blocking() {
+        let stubs = Stubs::new();
+        stop_unit(&stubs.cx(&[]), "x.service").await.unwrap();
+
+        assert_eq!(
+            stubs.args(SYSTEMCTL),
+            ["--user", "stop", "--no-block", "x.service"]
+        );
+    }
+
+    #[tokio::test]
+    async fn systemctl_errors_carry_stderr() {
+        let stubs = Stubs::new();
+        let cx = stubs.cx(&[("STUB_ERROR", "Unit x.service not loaded.")]);
+
+        let error = start_unit(&cx, "x.service").await.unwrap_err();
+        assert_eq!(error.to_string(), "Unit x.service not loaded.");
+    }
+
+    #[tokio::test]
+    async fn exports_the_session_environment() {
+        let stubs = Stubs::new();
+        let cx = stubs.cx(&[("XDG_SEAT", "seat0")]);
+        export_env(&cx, &["XDG_SEAT"]).await.unwrap();
+
+        assert_eq!(
+            stubs.args(SYSTEMCTL),
+            ["--user", "import-environment", "XDG_SEAT"]
+        );
+        assert!(stubs.env(SYSTEMCTL).contains(&"XDG_SEAT=seat0".to_string()));
+    }
+
+    #[test]
+    fn unescapes_values() {
+        let cases: [(&[u8], &[u8]); 7] = [
+            (b"plain", b"plain"),
+            (b"$'a\\nb'", b"a\nb"),
+            (b"$'tab\\there'", b"tab\there"),
+            (b"$'\\x41\\x7e'", b"A~"),
+            (b"$'quote\\'s \\\\'", b"quote's \\"),
+            // Not a valid escape, kept as it is
+            (b"$'\\xzz'", b"\\xzz"),
+            // Only fully quoted values are escaped
+            (b"$'open", b"$'open"),
+        ];
+
+        for (value, expected) in cases {
+            assert_eq!(unescape(value), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_manager_environment() {
+        let stubs = Stubs::new();
+        let output = "LANG=C.UTF-8\nMOTD=$'hello\\nworld'\nEMPTY=\nnot a variable\n";
+        let cx = stubs.cx(&[("STUB_OUTPUT", output)]);
+
+        let env = manager_environment(&cx).await.unwrap();
+        let expected: Vec<(OsString, OsString)> =
+            [("LANG", "C.UTF-8"), ("MOTD", "hello\nworld"), ("EMPTY", "")]
+                .into_iter()
+                .map(|(k, v)| (k.into(), v.into()))
+                .collect();
+
+        assert_eq!(env, expected);
+        assert_eq!(stubs.args(SYSTEMCTL), ["--user", "show-environment"]);
+    }
+}
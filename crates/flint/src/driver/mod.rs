@@ -5,7 +5,7 @@ use bon::Builder;
 use envy::define_env;
 
 // https://www.freedesktop.org/software/systemd/man/latest/pam_systemd.html#type=
-define_env!(SessionTypeEnv(String) = "XDG_SESSION_TYPE");
+define_env!(pub SessionTypeEnv(String) = "XDG_SESSION_TYPE");
 
 pub enum Kind {
     Graphical,
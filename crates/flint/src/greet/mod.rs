@@ -1,8 +1,260 @@
-use anyhow::Result;
+mod tty;
+
+use anyhow::{Context, Result};
 use dyn_utils::dyn_trait;
+use flint_pam::{
+    MessageLevel,
+    bridge::{Conversation, Request},
+};
+use futures_util::{
+    FutureExt,
+    future::{self, BoxFuture},
+};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+
+use crate::{
+    SeatContext,
+    config::CONFIG,
+    core::{
+        SessionClass,
+        login::{self, LoggedIn},
+    },
+    seat::{SeatID, view::View},
+    utils::{tty::VT, warn::WarnExt},
+};
 
 #[dyn_trait]
 pub trait Greeter {
-    async fn start() -> Self;
+    // class is either Greeter or LockScreen
+    async fn start(view: &View, class: SessionClass) -> Result<Self>;
     async fn display(&mut self, message: String) -> Result<()>;
+    async fn prompt(&mut self, message: String, echo: bool) -> Result<String>;
+}
+
+pub type GreeterObject = Box<dyn DynGreeter>;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum GreeterKind {
+    #[default]
+    Tty,
+}
+
+pub async fn greeter(kind: GreeterKind, view: &View, class: SessionClass) -> Result<GreeterObject> {
+    Ok(match kind {
+        GreeterKind::Tty => Box::new(tty::TtyGreeter::start(view, class).await?),
+    })
+}
+
+// Answers PAM through the greeter, until PAM is done asking.
+// On error the conversation is dropped, which makes PAM give up.
+pub async fn converse(greeter: &mut GreeterObject, mut conversation: Conversation) -> Result<()> {
+    while let Some(request) = conversation.next().await {
+        match request {
+            Request::Prompt { text, show, reply } => {
+                let answer = greeter.prompt(text, show).await?;
+                // PAM may have given up waiting, nothing to do then
+                let _ = reply.send(answer);
+            }
+            Request::Message { text, level } => {
+                let text = match level {
+                    MessageLevel::Error => format!("error: {text}"),
+                    MessageLevel::Info => text,
+                };
+                greeter.display(text).await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Keeps a greeter running on the seat until it is shut down.
+// The greeter is restarted whenever the view of the seat changes, and after every session.
+pub async fn run(seat: SeatContext) {
+    let start = async |view: &View| {
+        let kind = CONFIG.get().greeter_for(&seat.id);
+        greeter(kind, view, SessionClass::Greeter).await
+    };
+
+    greet_seat(&seat, start, login::login).await
+}
+
+// Split from run, so that tests can bring their own greeter and login
+async fn greet_seat(
+    seat: &SeatContext,
+    start: impl AsyncFn(&View) -> Result<GreeterObject>,
+    login: impl AsyncFn(&SeatID, &View, &mut GreeterObject) -> Result<LoggedIn>,
+) {
+    let mut view = seat.view();
+    let shutdown = seat.shutdown().clone();
+
+    // Shown once the greeter is back, i.e. how the last session ended
+    let mut notice: Option<String> = None;
+
+    loop {
+        let current = view.borrow_and_update().clone();
+
+        let mut greeter = start(&current)
+            .await
+            .context("Failed to start greeter")
+            .warn();
+
+        let login = async {
+            // Without a greeter, all we can do is wait for the view to change
+            let Some(greeter) = &mut greeter else {
+                return future::pending().await;
+            };
+
+            if let Some(notice) = notice.take() {
+                greeter
+                    .display(notice)
+                    .await
+                    .context("Greeter failed to display a message")
+                    .warn();
+            }
+
+            login(&seat.id, &current, greeter).await
+        };
+
+        let login = tokio::select! {
+            _ = shutdown.cancelled() => break,
+            changed = view.changed() => match changed {
+                Ok(()) => {
+                    debug!("View changed, restarting greeter");
+                    continue;
+                }
+                // The seat is gone
+                Err(_) => break,
+            },
+            login = login => login,
+        };
+
+        // The session takes over the view
+        drop(greeter);
+
+        notice = Some(match login {
+            Ok(session) => {
+                let username = session.username().to_string();
+                let ended = session.run(seat, seat.new_session()).await;
+
+                match &ended {
+                    Ok(status) => info!("Session of {username} ended with {status}"),
+                    Err(e) => warn!("Session of {username} failed: {e:?}"),
+                }
+                login::end_notice(&ended)
+            }
+            Err(e) => {
+                warn!("Login on seat {} failed: {e:?}", seat.id.as_str());
+                login::failure_notice(&e)
+            }
+        });
+
+        if shutdown.is_cancelled() {
+            break;
+        }
+    }
+}
+
+// A one-off greeter on a vt of its own, for another user while the session on the seat is locked.
+// Boxed, as the session it starts may be locked in turn.
+pub fn switch_user(seat: SeatContext) -> BoxFuture<'static, ()> {
+    async move {
+        let switched = async {
+            let view = View::Vt(VT::first_free()?);
+            let kind = CONFIG.get().greeter_for(&seat.id);
+
+            let mut greeter = greeter(kind, &view, SessionClass::Greeter)
+                .await
+                .context("Failed to start greeter")?;
+
+            let session = tokio::select! {
+                _ = seat.shutdown().cancelled() => return Ok(()),
+                login = login::login(&seat.id, &view, &mut greeter) => login?,
+            };
+
+            drop(greeter);
+
+            let username = session.username().to_string();
+            let status = session.run(&seat, seat.new_session()).await?;
+            info!("Session of {username} ended with {status}");
+
+            anyhow::Ok(())
+        };
+
+        // Either way, the lock screen comes back
+        if let Err(e) = switched.await {
+            warn!("Switching user on seat {} failed: {e:?}", seat.id.as_str());
+        }
+    }
+    .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::sync::mpsc::{self, UnboundedReceiver};
+
+    use super::*;
+    use crate::{SeatHandle, utils::shutdown::Shutdown, utils::tty::VtNumber};
+
+    // Nobody is at this greeter, so a login never gets anywhere
+    struct Idle;
+
+    impl Greeter for Idle {
+        async fn start(_view: &View, _class: SessionClass) -> Result<Self> {
+            Ok(Self)
+        }
+
+        async fn display(&mut self, _message: String) -> Result<()> {
+            Ok(())
+        }
+
+        async fn prompt(&mut self, _message: String, _echo: bool) -> Result<String> {
+            future::pending().await
+        }
+    }
+
+    async fn next(started: &mut UnboundedReceiver<View>) -> View {
+        tokio::time::timeout(Duration::from_secs(1), started.recv())
+            .await
+            .expect("greeter did not start in time")
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn restarts_the_greeter_when_the_view_changes() {
+        let id = SeatID("seat1".to_string());
+        let handle = SeatHandle::new(
+            View::Seat(id.clone()),
+            &Shutdown::root(Duration::from_secs(1)),
+        );
+        let seat = handle.context(&id);
+
+        let (started_tx, mut started) = mpsc::unbounded_channel();
+        let start = async |view: &View| {
+            started_tx.send(view.clone()).unwrap();
+            Ok(Box::new(Idle) as GreeterObject)
+        };
+        let login = async |_: &SeatID, _: &View, greeter: &mut GreeterObject| {
+            greeter.prompt(String::new(), true).await?;
+            unreachable!("nobody answers an idle greeter")
+        };
+
+        let greeting = tokio::spawn(async move { greet_seat(&seat, start, login).await });
+        assert!(next(&mut started).await == View::Seat(id.clone()));
+
+        let vt = View::Vt(VtNumber::new(2).unwrap());
+        handle.update(vt.clone());
+        assert!(next(&mut started).await == vt);
+
+        // Gone with the seat
+        handle.shutdown();
+        tokio::time::timeout(Duration::from_secs(1), greeting)
+            .await
+            .expect("greeter outlived its seat")
+            .unwrap();
+    }
 }
@@ -0,0 +1,49 @@
+use std::os::fd::OwnedFd;
+
+use anyhow::{Context, Result, bail};
+
+use super::Greeter;
+use crate::{core::SessionClass, seat::view::View, utils::tty::VT};
+
+// A plain text greeter, drawn directly on the vt
+pub struct TtyGreeter {
+    vt: VT<OwnedFd>,
+}
+
+impl Greeter for TtyGreeter {
+    async fn start(view: &View, class: SessionClass) -> Result<Self> {
+        let View::Vt(number) = view else {
+            bail!("The text greeter can only run on a vt")
+        };
+
+        let vt = VT::open(*number)?;
+        vt.activate()
+            .context("Failed to switch to the greeter vt")?;
+        vt.clear()?;
+
+        if class == SessionClass::LockScreen {
+            vt.write_all(b"This session is locked\n\n")?;
+        }
+
+        Ok(Self { vt })
+    }
+
+    async fn display(&mut self, message: String) -> Result<()> {
+        self.vt.write_all(message.as_bytes())?;
+        self.vt.write_all(b"\n")?;
+        Ok(())
+    }
+
+    async fn prompt(&mut self, message: String, echo: bool) -> Result<String> {
+        self.vt.write_all(message.as_bytes())?;
+
+        // Cancelled along with the prompt, i.e. when the view changes
+        let line = self
+            .vt
+            .read_line(echo)
+            .await
+            .context("Reading from the greeter vt failed")?;
+
+        Ok(line)
+    }
+}
@@ -1,5 +1,7 @@
 #![allow(dead_code)]
 
+mod check;
+mod config;
 mod core;
 mod driver;
 mod greet;
@@ -13,62 +15,197 @@ mod utils;
 
 use std::{
     collections::HashMap,
-    io::{BufReader, ErrorKind},
-    path::PathBuf,
-    sync::OnceLock,
+    path::{Path, PathBuf},
+    pin::Pin,
+    process::ExitCode,
+    sync::{
+        Arc, LazyLock, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
 };
 
 use anyhow::{Context, Result};
 use argh::FromArgs;
+use futures_util::{
+    FutureExt, Stream, StreamExt,
+    future::{self, BoxFuture},
+    stream::{self, FuturesUnordered},
+};
+use inotify::{Inotify, WatchMask};
 
-use fs_err::File;
 use hazymacros::newtype;
-use serde::{Deserialize, Serialize};
-use static_reload::{Resource, ResourceCell};
-use tokio::sync::broadcast;
+use tokio::{
+    signal::unix::{SignalKind, signal},
+    sync::{broadcast, watch},
+};
+use tracing::{debug, info, warn};
 
 use crate::{
-    seat::{SeatEvent, SeatID, SeatManagerObject, view::View},
-    utils::warn::WarnExt,
+    config::CONFIG,
+    seat::{
+        SeatBackend, SeatEvent, SeatID, SeatManagerObject, SeatProperties, seat_manager, view::View,
+    },
+    user::ProviderKind,
+    utils::{shutdown::Shutdown, warn::WarnExt},
 };
 
-#[derive(Serialize, Deserialize, Default)]
-pub struct Config {
-    #[allow(dead_code)]
-    version: Option<String>,
+newtype!(SessionID = u64);
+
+// How long each level of the shutdown tree waits for its children
+const GLOBAL_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(15);
+const SEAT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+const SESSION_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+// A seat that does not answer within this is skipped
+const SEAT_QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Sessions currently running on a seat, by id
+type Sessions = Arc<Mutex<HashMap<SessionID, Shutdown>>>;
+
+static NEXT_SESSION_ID: AtomicU64 = AtomicU64::new(0);
+
+// Asks every running session to lock, see handle_lock
+static LOCK_REQUESTS: LazyLock<broadcast::Sender<()>> = LazyLock::new(|| broadcast::channel(1).0);
+
+// What runs on a seat: its greeter, and through it the sessions
+type SeatTask = Box<dyn Fn(SeatContext) -> BoxFuture<'static, ()>>;
+
+// The state flint keeps for every seat it manages.
+// Greeter and sessions on the seat follow the view through a watch channel.
+struct SeatHandle {
+    view: watch::Sender<View>,
+    shutdown: Shutdown,
+    sessions: Sessions,
 }
 
-pub static CONFIG: ResourceCell<Config> = ResourceCell::new();
+impl SeatHandle {
+    fn new(view: View, parent: &Shutdown) -> Self {
+        Self {
+            view: watch::Sender::new(view),
+            shutdown: parent.child(SEAT_SHUTDOWN_TIMEOUT),
+            sessions: Sessions::default(),
+        }
+    }
 
-impl Resource for Config {
-    type Definition = PathBuf;
-    type Error = anyhow::Error;
+    fn start(id: &SeatID, view: View, parent: &Shutdown, task: &SeatTask) -> Self {
+        let seat = Self::new(view, parent);
+        seat.shutdown.spawn(task(seat.context(id)));
+        seat
+    }
 
-    async fn load(definition: &Self::Definition) -> Result<Self> {
-        let file = match File::open(definition) {
-            Err(e) if matches!(e.kind(), ErrorKind::NotFound) => return Ok(Config::default()),
-            other => other,
-        }?;
+    fn context(&self, id: &SeatID) -> SeatContext {
+        SeatContext {
+            id: id.clone(),
+            view: self.view.subscribe(),
+            shutdown: self.shutdown.clone(),
+            sessions: self.sessions.clone(),
+        }
+    }
+
+    fn update(&self, view: View) {
+        self.view
+            .send_if_modified(|current| match *current == view {
+                true => false,
+                false => {
+                    *current = view;
+                    true
+                }
+            });
+    }
 
-        let buf = BufReader::new(file);
+    // Every session stops its own process when cancelled.
+    // The parent waits for the seat to drain, so this does not block.
+    fn shutdown(self) {
+        let sessions = self.sessions.lock().unwrap().len();
+        if sessions > 0 {
+            info!("Stopping {sessions} session(s)");
+        }
 
-        Ok(serde_json::from_reader(buf)?)
+        self.shutdown.cancel();
     }
 }
 
-newtype!(SessionID = u64);
+// The part of a seat its greeter gets to see
+#[derive(Clone)]
+pub struct SeatContext {
+    pub id: SeatID,
+    view: watch::Receiver<View>,
+    shutdown: Shutdown,
+    sessions: Sessions,
+}
+
+impl SeatContext {
+    pub fn view(&self) -> watch::Receiver<View> {
+        self.view.clone()
+    }
+
+    pub fn shutdown(&self) -> &Shutdown {
+        &self.shutdown
+    }
+
+    // Fires whenever running sessions should be locked
+    pub fn lock_requests(&self) -> broadcast::Receiver<()> {
+        LOCK_REQUESTS.subscribe()
+    }
+
+    // Sessions each get their own node under the seat,
+    // so that one of them can be stopped without touching the others
+    pub fn new_session(&self) -> SessionSlot {
+        let id = SessionID(NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed));
+        let shutdown = self.shutdown.child(SESSION_SHUTDOWN_TIMEOUT);
 
-// broadcast is wrong here, session-triggered shutdown will propagate to seat,
-// seat to global. Need hierarchical channels.
-type ShutdownRx = broadcast::Receiver<()>;
-type ShutdownTx = broadcast::Sender<()>;
+        self.sessions.lock().unwrap().insert(id, shutdown.clone());
+
+        SessionSlot {
+            id,
+            shutdown,
+            sessions: self.sessions.clone(),
+        }
+    }
+}
+
+// Registers a session with its seat for as long as it is kept.
+// Its node is cancelled on drop, so that the seat stops tracking it.
+pub struct SessionSlot {
+    pub id: SessionID,
+    pub shutdown: Shutdown,
+    sessions: Sessions,
+}
+
+impl Drop for SessionSlot {
+    fn drop(&mut self) {
+        self.sessions.lock().unwrap().remove(&self.id);
+        self.shutdown.cancel();
+    }
+}
+
+async fn query_seat(seat_manager: &SeatManagerObject, id: &SeatID) -> Option<SeatProperties> {
+    tokio::time::timeout(SEAT_QUERY_TIMEOUT, seat_manager.query(id))
+        .await
+        .map_err(anyhow::Error::from)
+        .flatten()
+        .with_context(|| format!("Failed to get properties of seat {}", id.as_str()))
+        .warn()
+}
 
 struct Flint {
     seat_manager: SeatManagerObject,
-    seats: HashMap<SeatID, View>,
+    seats: HashMap<SeatID, SeatHandle>,
+    seat_task: SeatTask,
+    shutdown: Shutdown,
 }
 
 impl Flint {
+    fn new(seat_manager: SeatManagerObject, seat_task: SeatTask, shutdown: Shutdown) -> Self {
+        Self {
+            seat_manager,
+            seats: HashMap::new(),
+            seat_task,
+            shutdown,
+        }
+    }
+
     // fn get_seat(&mut self, id: SeatID) -> Result<SeatHandle> {
     //     match self.seats.entry(id.clone()) {
     //         hash_map::Entry::Occupied(x) => Ok(SeatHandle(x)),
@@ -76,54 +213,616 @@ impl Flint {
     //     }
     // }
 
+    fn add_seat(&mut self, id: SeatID, props: SeatProperties) {
+        Self::insert_seat(&mut self.seats, &self.seat_task, &self.shutdown, id, props);
+    }
+
+    // Takes the fields apart, so that it can run while the seat manager is borrowed
+    fn insert_seat(
+        seats: &mut HashMap<SeatID, SeatHandle>,
+        seat_task: &SeatTask,
+        shutdown: &Shutdown,
+        id: SeatID,
+        props: SeatProperties,
+    ) {
+        let seat = SeatHandle::start(&id, props.view, shutdown, seat_task);
+
+        if let Some(stale) = seats.insert(id, seat) {
+            stale.shutdown();
+        }
+    }
+
     async fn new_seat(&mut self, id: SeatID) {
-        let props = self
-            .seat_manager
-            .query(&id)
+        if let Some(props) = query_seat(&self.seat_manager, &id).await {
+            self.add_seat(id, props);
+        }
+    }
+
+    // Seats are queried concurrently, so a slow one does not hold up the others.
+    // Each seat is set up as soon as its own query is done.
+    async fn init_seats(&mut self) {
+        let Some(ids) = tokio::time::timeout(SEAT_QUERY_TIMEOUT, self.seat_manager.list_seats())
             .await
-            .context("Failed to get properties, skipping seat")
-            .warn();
+            .context("Timed out listing seats")
+            .warn()
+        else {
+            return;
+        };
 
-        if let Some(props) = props {
-            self.seats.insert(id, props.view);
-            // TODO: spawn greeter here
+        let Self {
+            seat_manager,
+            seats,
+            seat_task,
+            shutdown,
+        } = self;
+        let seat_manager = &*seat_manager;
+
+        let mut queries: FuturesUnordered<_> = ids
+            .into_iter()
+            .map(|id| async move {
+                let props = query_seat(seat_manager, &id).await;
+                (id, props)
+            })
+            .collect();
+
+        while let Some((id, props)) = queries.next().await {
+            if let Some(props) = props {
+                Self::insert_seat(seats, seat_task, shutdown, id, props);
+            }
         }
     }
 
-    async fn run(mut self) {
-        // TODO: parallelize
-        for id in self.seat_manager.list_seats().await {
-            self.new_seat(id).await;
+    async fn change_seat(&mut self, id: SeatID) {
+        // A seat we skipped before may have become usable
+        let Some(seat) = self.seats.get(&id) else {
+            return self.new_seat(id).await;
+        };
+
+        let Some(props) = query_seat(&self.seat_manager, &id).await else {
+            return;
+        };
+
+        // The vt of seat0 is ours already, a query would just allocate another free one
+        if matches!(*seat.view.borrow(), View::Vt(_)) && matches!(props.view, View::Vt(_)) {
+            return;
         }
 
-        while let Some((id, event)) = self.seat_manager.next_event().await {
+        seat.update(props.view);
+    }
+
+    fn remove_seat(&mut self, id: SeatID) {
+        match self.seats.remove(&id) {
+            Some(seat) => {
+                info!("Seat {} was removed, shutting it down", id.as_str());
+                seat.shutdown();
+            }
+            None => debug!("Ignoring removal of unmanaged seat {}", id.as_str()),
+        }
+    }
+
+    async fn run(mut self) {
+        self.init_seats().await;
+
+        loop {
+            let event = tokio::select! {
+                event = self.seat_manager.next_event() => event,
+                _ = self.shutdown.cancelled() => break,
+            };
+
+            let Some((id, event)) = event else {
+                // Seats we already manage keep running
+                warn!("Seat manager stopped reporting events");
+                self.shutdown.cancelled().await;
+                break;
+            };
+
             match event {
                 SeatEvent::Added => self.new_seat(id).await,
-                SeatEvent::Changed => todo!(), // TODO: here, view can change if vt
-                SeatEvent::Removed => todo!(),
+                SeatEvent::Changed => self.change_seat(id).await,
+                SeatEvent::Removed => self.remove_seat(id),
             }
         }
+
+        self.shutdown.shutdown().await;
     }
 }
 
+#[derive(FromArgs)]
+#[argh(subcommand)]
+enum Command {
+    CheckConfig(check::CheckConfig),
+}
+
 #[derive(FromArgs)]
 /// flint session manager
 struct Args {
+    #[argh(subcommand)]
+    command: Option<Command>,
+
     /// configuration path
     #[argh(option)]
     #[argh(default = r#""/etc/flint.toml".into()"#)]
     config: PathBuf,
 
+    /// reload the configuration whenever the file changes
+    #[argh(switch)]
+    watch_config: bool,
+
     /// TODO
     #[argh(switch)]
     can_suspend_home: bool,
 }
 
+fn log_startup_summary() {
+    let seat_backends: Vec<_> = SeatBackend::ALL
+        .into_iter()
+        .filter(|x| x.compiled_in())
+        .map(SeatBackend::name)
+        .collect();
+
+    let user_providers: Vec<_> = ProviderKind::ALL
+        .into_iter()
+        .filter(|x| x.compiled_in())
+        .map(ProviderKind::name)
+        .collect();
+
+    info!("Starting flint {}", env!("CARGO_PKG_VERSION"));
+    info!("Seat backends: {}", seat_backends.join(", "));
+    info!("User providers: {}", user_providers.join(", "));
+}
+
+// Cancels the global shutdown node on the first SIGTERM or SIGINT
+fn handle_signals(shutdown: Shutdown) -> Result<()> {
+    let mut sigterm = signal(SignalKind::terminate()).context("Failed to handle SIGTERM")?;
+    let mut sigint = signal(SignalKind::interrupt()).context("Failed to handle SIGINT")?;
+
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = sigterm.recv() => info!("Received SIGTERM, shutting down"),
+            _ = sigint.recv() => info!("Received SIGINT, shutting down"),
+        }
+        shutdown.cancel();
+    });
+
+    Ok(())
+}
+
+// Locks every running session on SIGUSR1
+fn handle_lock(shutdown: Shutdown) -> Result<()> {
+    let mut sigusr1 = signal(SignalKind::user_defined1()).context("Failed to handle SIGUSR1")?;
+
+    shutdown.clone().spawn(async move {
+        loop {
+            tokio::select! {
+                _ = shutdown.cancelled() => break,
+                _ = sigusr1.recv() => info!("Received SIGUSR1, locking sessions"),
+            }
+
+            // Nobody to lock if there are no sessions
+            let _ = LOCK_REQUESTS.send(());
+        }
+    });
+
+    Ok(())
+}
+
+async fn reload_config() {
+    match CONFIG.reload().await {
+        Ok(()) => info!("Configuration reloaded"),
+        Err(e) => warn!("Failed to reload configuration, keeping the previous one: {e:?}"),
+    }
+}
+
+// Editors usually replace the file instead of writing to it,
+// so we watch the directory and filter by name
+fn watch_config(path: &Path) -> Result<impl Stream<Item = ()> + Send + 'static> {
+    let dir = path
+        .parent()
+        .filter(|x| !x.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let name = path
+        .file_name()
+        .context("Configuration path has no file name")?
+        .to_owned();
+
+    let inotify = Inotify::init().context("Failed to initialize inotify")?;
+    inotify
+        .watches()
+        .add(dir, WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO)
+        .with_context(|| format!("Failed to watch {dir:?}"))?;
+
+    let events = inotify.into_event_stream([0; 4096])?;
+
+    Ok(events.filter_map(move |event| {
+        future::ready(match event {
+            Ok(event) if event.name.as_deref() == Some(name.as_os_str()) => Some(()),
+            _ => None,
+        })
+    }))
+}
+
+// Reloads the configuration on SIGHUP, and on file changes if requested
+fn handle_reload(path: &Path, watch: bool, shutdown: Shutdown) -> Result<()> {
+    let mut sighup = signal(SignalKind::hangup()).context("Failed to handle SIGHUP")?;
+
+    let mut changes: Pin<Box<dyn Stream<Item = ()> + Send>> = match watch {
+        true => Box::pin(watch_config(path)?),
+        false => Box::pin(stream::pending()),
+    };
+
+    shutdown.clone().spawn(async move {
+        loop {
+            tokio::select! {
+                _ = shutdown.cancelled() => break,
+                _ = sighup.recv() => info!("Received SIGHUP, reloading configuration"),
+                Some(()) = changes.next() => info!("Configuration file changed, reloading"),
+            }
+
+            reload_config().await;
+        }
+    });
+
+    Ok(())
+}
+
 #[tokio::main(flavor = "current_thread")]
-async fn main() -> Result<()> {
-    tracing_subscriber::fmt().init();
+async fn main() -> Result<ExitCode> {
+    // stdout is reserved for command output, such as check-config --json
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .init();
     let args: Args = argh::from_env();
-    CONFIG.init(args.config).await?;
 
-    Ok(())
+    if let Some(Command::CheckConfig(check)) = args.command {
+        return Ok(check::run(&args.config, check).await);
+    }
+
+    CONFIG.init(args.config.clone()).await?;
+
+    log_startup_summary();
+
+    // Probing a backend can take a while, a signal meanwhile still stops us
+    let shutdown = Shutdown::root(GLOBAL_SHUTDOWN_TIMEOUT);
+    handle_signals(shutdown.clone())?;
+
+    let seat_manager = tokio::select! {
+        seat_manager = seat_manager(CONFIG.get().backends.seat) => seat_manager?,
+        _ = shutdown.cancelled() => {
+            info!("Shutdown requested before any seat was set up");
+            return Ok(ExitCode::SUCCESS);
+        }
+    };
+
+    handle_reload(&args.config, args.watch_config, shutdown.clone())?;
+    handle_lock(shutdown.clone())?;
+
+    let seat_task: SeatTask = Box::new(|seat| greet::run(seat).boxed());
+    Flint::new(seat_manager, seat_task, shutdown).run().await;
+    info!("Shutdown complete");
+
+    Ok(ExitCode::SUCCESS)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+        task::{JoinHandle, LocalSet},
+    };
+
+    use super::*;
+    use crate::{seat::SeatManager, utils::tty::VtNumber};
+
+    type Views = Arc<Mutex<HashMap<SeatID, View>>>;
+
+    // Replays seat events fed by the test.
+    // Queries answer with whatever view the test has set for the seat.
+    struct ScriptedSeats {
+        initial: Vec<SeatID>,
+        events: UnboundedReceiver<(SeatID, SeatEvent)>,
+        views: Views,
+    }
+
+    impl SeatManager for ScriptedSeats {
+        fn libseat_backend() -> &'static str {
+            "scripted"
+        }
+
+        async fn list_seats(&mut self) -> Vec<SeatID> {
+            self.initial.clone()
+        }
+
+        async fn next_event(&mut self) -> Option<(SeatID, SeatEvent)> {
+            self.events.recv().await
+        }
+
+        async fn query(&self, id: &SeatID) -> Result<SeatProperties> {
+            let view = self.views.lock().unwrap().get(id).cloned();
+
+            Ok(SeatProperties {
+                view: view.context("No such seat")?,
+                can_graphical: false,
+            })
+        }
+    }
+
+    // What a seat task has seen, in order
+    #[derive(PartialEq, Eq)]
+    enum Seen {
+        Started(View),
+        ViewChanged(View),
+        SessionStopped,
+    }
+
+    // Every seat runs a single session, and reports the view like greet::run follows it
+    fn seat_task(seen: UnboundedSender<(SeatID, Seen)>) -> SeatTask {
+        Box::new(move |seat| {
+            let seen = seen.clone();
+            let session = seat.new_session();
+
+            async move {
+                let report = |x| seen.send((seat.id.clone(), x)).unwrap();
+                let mut view = seat.view();
+
+                report(Seen::Started(view.borrow_and_update().clone()));
+
+                loop {
+                    tokio::select! {
+                        _ = session.shutdown.cancelled() => break,
+                        Ok(()) = view.changed() => {
+                            report(Seen::ViewChanged(view.borrow_and_update().clone()))
+                        }
+                    }
+                }
+
+                drop(session);
+                report(Seen::SessionStopped);
+            }
+            .boxed()
+        })
+    }
+
+    struct Harness {
+        events: UnboundedSender<(SeatID, SeatEvent)>,
+        views: Views,
+        seen: UnboundedReceiver<(SeatID, Seen)>,
+        shutdown: Shutdown,
+        flint: JoinHandle<()>,
+    }
+
+    impl Harness {
+        fn start(seats: &[(SeatID, View)]) -> Self {
+            let (events, events_rx) = mpsc::unbounded_channel();
+            let (seen_tx, seen) = mpsc::unbounded_channel();
+            let views = Views::new(Mutex::new(seats.iter().cloned().collect()));
+
+            let seat_manager = ScriptedSeats {
+                initial: seats.iter().map(|(id, _)| id.clone()).collect(),
+                events: events_rx,
+                views: views.clone(),
+            };
+
+            let shutdown = Shutdown::root(Duration::from_secs(1));
+            let flint = Flint::new(Box::new(seat_manager), seat_task(seen_tx), shutdown.clone());
+
+            Self {
+                events,
+                views,
+                seen,
+                shutdown,
+                flint: tokio::task::spawn_local(flint.run()),
+            }
+        }
+
+        fn set_view(&self, id: &SeatID, view: Option<View>) {
+            let mut views = self.views.lock().unwrap();
+            match view {
+                Some(view) => views.insert(id.clone(), view),
+                None => views.remove(id),
+            };
+        }
+
+        fn send(&self, id: &SeatID, event: SeatEvent) {
+            self.events.send((id.clone(), event)).unwrap();
+        }
+
+        async fn expect(&mut self, id: &SeatID, expected: Seen) {
+            let (seat, seen) = tokio::time::timeout(Duration::from_secs(1), self.seen.recv())
+                .await
+                .expect("seat task did not report in time")
+                .expect("all seat tasks are gone");
+
+            assert!(seat == *id && seen == expected);
+        }
+
+        async fn expect_nothing(&mut self) {
+            let seen = tokio::time::timeout(Duration::from_millis(100), self.seen.recv()).await;
+            assert!(seen.is_err());
+        }
+
+        async fn stop(self) {
+            self.shutdown.cancel();
+            self.flint.await.unwrap();
+        }
+    }
+
+    // The seat manager object is not Send, so flint runs on the test's own thread
+    async fn local(test: impl Future<Output = ()>) {
+        LocalSet::new().run_until(test).await
+    }
+
+    fn seat(id: &str) -> SeatID {
+        SeatID(id.to_string())
+    }
+
+    fn vt(number: u8) -> View {
+        View::Vt(VtNumber::new(number).unwrap())
+    }
+
+    #[tokio::test]
+    async fn ended_sessions_leave_their_seat() {
+        let handle = SeatHandle::new(vt(2), &Shutdown::root(Duration::from_secs(1)));
+        let slot = handle.context(&seat("seat1")).new_session();
+        let node = slot.shutdown.clone();
+
+        drop(slot);
+        assert!(node.is_cancelled());
+        assert!(handle.sessions.lock().unwrap().is_empty());
+        assert!(!handle.shutdown.is_cancelled());
+    }
+
+    #[tokio::test]
+    async fn starts_initial_seats() {
+        local(async {
+            let seat1 = seat("seat1");
+            let mut harness = Harness::start(&[(seat1.clone(), vt(2))]);
+
+            harness.expect(&seat1, Seen::Started(vt(2))).await;
+            harness.stop().await;
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn skips_seats_that_fail_to_query() {
+        local(async {
+            let (seat1, seat2) = (seat("seat1"), seat("seat2"));
+            let mut harness = Harness::start(&[(seat1.clone(), vt(2)), (seat2.clone(), vt(3))]);
+            harness.set_view(&seat1, None);
+
+            harness.expect(&seat2, Seen::Started(vt(3))).await;
+            harness.expect_nothing().await;
+            harness.stop().await;
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn starts_added_seats() {
+        local(async {
+            let seat1 = seat("seat1");
+            let mut harness = Harness::start(&[]);
+
+            harness.set_view(&seat1, Some(View::Seat(seat1.clone())));
+            harness.send(&seat1, SeatEvent::Added);
+
+            harness
+                .expect(&seat1, Seen::Started(View::Seat(seat1.clone())))
+                .await;
+            harness.stop().await;
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn replaces_seats_added_twice() {
+        local(async {
+            let seat1 = seat("seat1");
+            let mut harness = Harness::start(&[(seat1.clone(), vt(2))]);
+            harness.expect(&seat1, Seen::Started(vt(2))).await;
+
+            // The stale seat is shut down, not just forgotten
+            harness.set_view(&seat1, Some(vt(3)));
+            harness.send(&seat1, SeatEvent::Added);
+
+            let mut seen = Vec::new();
+            for _ in 0..2 {
+                seen.push(harness.seen.recv().await.unwrap().1);
+            }
+            assert!(seen.contains(&Seen::SessionStopped));
+            assert!(seen.contains(&Seen::Started(vt(3))));
+
+            harness.stop().await;
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn tells_seats_about_changed_views() {
+        local(async {
+            let seat1 = seat("seat1");
+            let mut harness = Harness::start(&[(seat1.clone(), View::Seat(seat1.clone()))]);
+            harness
+                .expect(&seat1, Seen::Started(View::Seat(seat1.clone())))
+                .await;
+
+            harness.set_view(&seat1, Some(vt(2)));
+            harness.send(&seat1, SeatEvent::Changed);
+            harness.expect(&seat1, Seen::ViewChanged(vt(2))).await;
+
+            // Nothing to tell if the view stayed the same
+            harness.send(&seat1, SeatEvent::Changed);
+            harness.expect_nothing().await;
+
+            harness.stop().await;
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn keeps_the_vt_of_seat0() {
+        local(async {
+            let seat0 = SeatID::seat0();
+            let mut harness = Harness::start(&[(seat0.clone(), vt(2))]);
+            harness.expect(&seat0, Seen::Started(vt(2))).await;
+
+            // Querying seat0 again would only find another free vt
+            harness.set_view(&seat0, Some(vt(3)));
+            harness.send(&seat0, SeatEvent::Changed);
+            harness.expect_nothing().await;
+
+            harness.stop().await;
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn tears_down_removed_seats() {
+        local(async {
+            let (seat1, seat2) = (seat("seat1"), seat("seat2"));
+            let mut harness = Harness::start(&[(seat1.clone(), vt(2))]);
+            harness.expect(&seat1, Seen::Started(vt(2))).await;
+
+            harness.set_view(&seat1, None);
+            harness.send(&seat1, SeatEvent::Removed);
+            harness.expect(&seat1, Seen::SessionStopped).await;
+
+            // Neither a second removal nor an unknown seat stop the main loop
+            harness.send(&seat1, SeatEvent::Removed);
+            harness.send(&seat2, SeatEvent::Removed);
+            harness.set_view(&seat2, Some(vt(3)));
+            harness.send(&seat2, SeatEvent::Added);
+            harness.expect(&seat2, Seen::Started(vt(3))).await;
+
+            harness.stop().await;
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn shutdown_stops_every_session() {
+        local(async {
+            let (seat1, seat2) = (seat("seat1"), seat("seat2"));
+            let mut harness = Harness::start(&[(seat1.clone(), vt(2)), (seat2.clone(), vt(3))]);
+
+            let mut started = Vec::new();
+            for _ in 0..2 {
+                started.push(harness.seen.recv().await.unwrap().0);
+            }
+            assert!(started.contains(&seat1) && started.contains(&seat2));
+
+            harness.shutdown.cancel();
+
+            let mut stopped = Vec::new();
+            for _ in 0..2 {
+                let (id, seen) = harness.seen.recv().await.unwrap();
+                assert!(seen == Seen::SessionStopped);
+                stopped.push(id);
+            }
+            assert!(stopped.contains(&seat1) && stopped.contains(&seat2));
+
+            harness.flint.await.unwrap();
+        })
+        .await
+    }
 }
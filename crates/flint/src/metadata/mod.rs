@@ -1,4 +1,4 @@
-mod xdg;
+pub mod xdg;
 
 use std::{collections::HashMap, path::PathBuf};
 
@@ -98,6 +98,6 @@ impl DefinedSessions {
     }
 }
 
-pub async fn load(config: super::Config) -> DefinedSessions {
+pub async fn load(config: crate::config::Config) -> DefinedSessions {
     todo!()
 }
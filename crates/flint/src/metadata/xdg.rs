@@ -9,8 +9,8 @@ use std::{
 
 use crate::with_builder;
 
-const X11_SESSION_PATH: &str = "/usr/share/xsessions";
-const WAYLAND_SESSION_PATH: &str = "/usr/share/wayland-sessions";
+pub const X11_SESSION_PATH: &str = "/usr/share/xsessions";
+pub const WAYLAND_SESSION_PATH: &str = "/usr/share/wayland-sessions";
 
 // TODO
 // pub struct LocaleString {
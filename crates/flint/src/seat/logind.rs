@@ -1,3 +1,19 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    path::{Path, PathBuf},
+    pin::pin,
+    time::Duration,
+};
+
+use anyhow::{Context, Result, bail};
+use futures_util::StreamExt;
+use tracing::warn;
+use zlink::tokio::unix::Stream;
+
+use super::{SeatEvent, SeatID, SeatManager, SeatProperties, view::View};
+use crate::utils::warn::WarnExt;
+use varlink::{Login, LoginError, SeatRuntime};
+
 mod varlink {
     use serde::{Deserialize, Serialize};
     use zlink::{ReplyError, proxy};
@@ -11,6 +27,14 @@ mod varlink {
             &mut self,
             #[zlink(rename = "Id")] id: Option<&str>,
         ) -> zlink::Result<Result<ListSeatsOutput, LoginError>>;
+
+        /// Streams all current seats, one reply per seat.
+        #[zlink(rename = "ListSeats", more)]
+        async fn list_all_seats(
+            &mut self,
+        ) -> zlink::Result<
+            impl futures_util::Stream<Item = zlink::Result<Result<ListSeatsOutput, LoginError>>>,
+        >;
     }
 
     /// Output parameters for the ListSeats method.
@@ -83,4 +107,393 @@ mod varlink {
         /// The session leader process does not have a pidfd
         NoSessionPidfd,
     }
+
+    impl std::fmt::Display for LoginError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            std::fmt::Debug::fmt(self, f)
+        }
+    }
+}
+
+const SOCKET_PATH: &str = "/run/systemd/io.systemd.Login";
+
+// NOTE: io.systemd.Login has no way to subscribe to seat changes,
+// so we poll and diff against the last known state
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+// The parts of SeatRuntime that make a difference to flint
+#[derive(PartialEq, Eq)]
+struct SeatState {
+    can_graphical: bool,
+    can_tty: bool,
+}
+
+impl From<&SeatRuntime> for SeatState {
+    fn from(runtime: &SeatRuntime) -> Self {
+        Self {
+            can_graphical: runtime.can_graphical,
+            can_tty: runtime.can_tty,
+        }
+    }
+}
+
+pub struct Logind {
+    path: PathBuf,
+    conn: zlink::Connection<Stream>,
+    known: HashMap<SeatID, SeatState>,
+    pending: VecDeque<(SeatID, SeatEvent)>,
+}
+
+impl Logind {
+    pub async fn connect() -> Result<Self, zlink::Error> {
+        Self::connect_path(Path::new(SOCKET_PATH)).await
+    }
+
+    pub async fn connect_path(path: &Path) -> Result<Self, zlink::Error> {
+        let conn = zlink::tokio::unix::connect(path).await?;
+
+        Ok(Self {
+            path: path.to_owned(),
+            conn,
+            known: HashMap::new(),
+            pending: VecDeque::new(),
+        })
+    }
+
+    async fn fetch(&mut self) -> Result<HashMap<SeatID, SeatState>> {
+        let mut seats = HashMap::new();
+        let mut replies = pin!(self.conn.list_all_seats().await?);
+
+        while let Some(reply) = replies.next().await {
+            match reply? {
+                Ok(seat) => {
+                    seats.insert(SeatID(seat.context.id), SeatState::from(&seat.runtime));
+                }
+                // logind replies with an error instead of an empty stream
+                Err(LoginError::NoSuchSeat) => break,
+                Err(e) => bail!(e),
+            }
+        }
+
+        Ok(seats)
+    }
+
+    fn diff(&mut self, current: HashMap<SeatID, SeatState>) {
+        for id in self.known.keys() {
+            if !current.contains_key(id) {
+                self.pending.push_back((id.clone(), SeatEvent::Removed));
+            }
+        }
+
+        for (id, state) in &current {
+            match self.known.get(id) {
+                None => self.pending.push_back((id.clone(), SeatEvent::Added)),
+                Some(known) if known != state => {
+                    self.pending.push_back((id.clone(), SeatEvent::Changed))
+                }
+                Some(_) => (),
+            }
+        }
+
+        self.known = current;
+    }
+}
+
+impl SeatManager for Logind {
+    fn libseat_backend() -> &'static str {
+        "logind"
+    }
+
+    async fn list_seats(&mut self) -> Vec<SeatID> {
+        let Some(seats) = self
+            .fetch()
+            .await
+            .context("Failed to list seats from logind")
+            .warn()
+        else {
+            return Vec::new();
+        };
+
+        self.known = seats;
+        self.known.keys().cloned().collect()
+    }
+
+    async fn next_event(&mut self) -> Option<(SeatID, SeatEvent)> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Some(event);
+            }
+
+            tokio::time::sleep(POLL_INTERVAL).await;
+
+            match self.fetch().await {
+                Ok(current) => self.diff(current),
+                Err(e) => {
+                    warn!("Lost connection to logind, no longer following seats: {e:?}");
+                    return None;
+                }
+            }
+        }
+    }
+
+    async fn query(&self, id: &SeatID) -> Result<SeatProperties> {
+        // A separate connection, so that queries can run concurrently
+        let mut conn = zlink::tokio::unix::connect(&self.path).await?;
+
+        let seat = match conn.list_seats(Some(id.as_str())).await? {
+            Ok(seat) => seat,
+            Err(e) => bail!(e),
+        };
+
+        let runtime = seat.runtime;
+        if !runtime.can_graphical && !runtime.can_tty {
+            bail!(
+                "Seat {} supports neither graphical nor text sessions",
+                id.as_str()
+            );
+        }
+
+        Ok(SeatProperties {
+            view: View::for_seat(id.clone())?,
+            can_graphical: runtime.can_graphical,
+        })
+    }
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use std::{
+        collections::HashSet,
+        sync::{Arc, Mutex},
+    };
+
+    use serde_json::{Value, json};
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::{UnixListener, UnixStream},
+    };
+
+    use super::*;
+
+    #[derive(Clone)]
+    pub(crate) struct MockSeat {
+        id: &'static str,
+        can_graphical: bool,
+        can_tty: bool,
+    }
+
+    impl MockSeat {
+        pub(crate) fn new(id: &'static str) -> Self {
+            Self {
+                id,
+                can_graphical: true,
+                can_tty: true,
+            }
+        }
+
+        fn to_json(&self) -> Value {
+            json!({
+                "context": { "Id": self.id },
+                "runtime": {
+                    "CanTTY": self.can_tty,
+                    "CanGraphical": self.can_graphical,
+                    "IdleHint": false,
+                },
+            })
+        }
+    }
+
+    // Serves io.systemd.Login.ListSeats, the only method flint calls.
+    // Seats can be changed while it runs, and closing it drops every connection.
+    #[derive(Clone)]
+    pub(crate) struct MockLogind {
+        seats: Arc<Mutex<Vec<MockSeat>>>,
+        closed: Arc<Mutex<bool>>,
+    }
+
+    impl MockLogind {
+        pub(crate) fn start(dir: &Path, seats: Vec<MockSeat>) -> (Self, PathBuf) {
+            let path = dir.join("io.systemd.Login");
+            let listener = UnixListener::bind(&path).unwrap();
+
+            let mock = Self {
+                seats: Arc::new(Mutex::new(seats)),
+                closed: Arc::new(Mutex::new(false)),
+            };
+
+            let server = mock.clone();
+            tokio::spawn(async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    tokio::spawn(server.clone().serve(stream));
+                }
+            });
+
+            (mock, path)
+        }
+
+        fn set(&self, seats: Vec<MockSeat>) {
+            *self.seats.lock().unwrap() = seats;
+        }
+
+        fn close(&self) {
+            *self.closed.lock().unwrap() = true;
+        }
+
+        // Varlink messages are JSON objects, each terminated by a NUL byte
+        async fn send(stream: &mut BufReader<UnixStream>, message: Value) {
+            let mut bytes = serde_json::to_vec(&message).unwrap();
+            bytes.push(0);
+            stream.get_mut().write_all(&bytes).await.unwrap();
+        }
+
+        async fn serve(self, stream: UnixStream) {
+            let mut stream = BufReader::new(stream);
+
+            loop {
+                let mut request = Vec::new();
+                match stream.read_until(0, &mut request).await {
+                    Ok(0) | Err(_) => return,
+                    Ok(_) => request.pop(),
+                };
+
+                if *self.closed.lock().unwrap() {
+                    return;
+                }
+
+                let request: Value = serde_json::from_slice(&request).unwrap();
+                assert_eq!(request["method"], "io.systemd.Login.ListSeats");
+
+                let seats = self.seats.lock().unwrap().clone();
+                let no_such_seat = json!({
+                    "error": "io.systemd.Login.NoSuchSeat",
+                    "parameters": {},
+                });
+
+                if let Some(id) = request["parameters"]["Id"].as_str() {
+                    let reply = match seats.iter().find(|x| x.id == id) {
+                        Some(seat) => json!({ "parameters": seat.to_json() }),
+                        None => no_such_seat,
+                    };
+                    Self::send(&mut stream, reply).await;
+                    continue;
+                }
+
+                assert_eq!(
+                    request["more"], true,
+                    "listing all seats needs the more flag"
+                );
+
+                if seats.is_empty() {
+                    Self::send(&mut stream, no_such_seat).await;
+                    continue;
+                }
+
+                for (i, seat) in seats.iter().enumerate() {
+                    let continues = i + 1 < seats.len();
+                    let reply = json!({ "parameters": seat.to_json(), "continues": continues });
+                    Self::send(&mut stream, reply).await;
+                }
+            }
+        }
+    }
+
+    fn ids(seats: Vec<SeatID>) -> HashSet<String> {
+        seats.into_iter().map(|x| x.as_str().to_string()).collect()
+    }
+
+    async fn next_event(logind: &mut Logind) -> (String, SeatEvent) {
+        let (id, event) = tokio::time::timeout(POLL_INTERVAL * 3, logind.next_event())
+            .await
+            .expect("no event within three polls")
+            .expect("logind stopped reporting events");
+
+        (id.as_str().to_string(), event)
+    }
+
+    #[tokio::test]
+    async fn lists_all_seats() {
+        let dir = tempfile::tempdir().unwrap();
+        let (_mock, path) = MockLogind::start(
+            dir.path(),
+            vec![MockSeat::new("seat0"), MockSeat::new("seat1")],
+        );
+
+        let mut logind = Logind::connect_path(&path).await.unwrap();
+        let expected = ["seat0", "seat1"].map(String::from).into();
+
+        assert_eq!(ids(logind.list_seats().await), expected);
+    }
+
+    #[tokio::test]
+    async fn lists_no_seats() {
+        let dir = tempfile::tempdir().unwrap();
+        let (_mock, path) = MockLogind::start(dir.path(), Vec::new());
+
+        let mut logind = Logind::connect_path(&path).await.unwrap();
+        assert!(logind.list_seats().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn follows_seats() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mock, path) = MockLogind::start(dir.path(), vec![MockSeat::new("seat0")]);
+
+        let mut logind = Logind::connect_path(&path).await.unwrap();
+        logind.list_seats().await;
+
+        mock.set(vec![MockSeat::new("seat0"), MockSeat::new("seat1")]);
+        let (id, event) = next_event(&mut logind).await;
+        assert_eq!(id, "seat1");
+        assert!(matches!(event, SeatEvent::Added));
+
+        let mut changed = MockSeat::new("seat1");
+        changed.can_graphical = false;
+        mock.set(vec![MockSeat::new("seat0"), changed]);
+        let (id, event) = next_event(&mut logind).await;
+        assert_eq!(id, "seat1");
+        assert!(matches!(event, SeatEvent::Changed));
+
+        mock.set(vec![MockSeat::new("seat0")]);
+        let (id, event) = next_event(&mut logind).await;
+        assert_eq!(id, "seat1");
+        assert!(matches!(event, SeatEvent::Removed));
+    }
+
+    #[tokio::test]
+    async fn stops_when_logind_goes_away() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mock, path) = MockLogind::start(dir.path(), vec![MockSeat::new("seat0")]);
+
+        let mut logind = Logind::connect_path(&path).await.unwrap();
+        logind.list_seats().await;
+
+        mock.close();
+        let event = tokio::time::timeout(POLL_INTERVAL * 3, logind.next_event())
+            .await
+            .unwrap();
+        assert!(event.is_none());
+    }
+
+    #[tokio::test]
+    async fn query_maps_capabilities() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let mut text_only = MockSeat::new("seat1");
+        text_only.can_graphical = false;
+        let mut unusable = MockSeat::new("seat2");
+        unusable.can_graphical = false;
+        unusable.can_tty = false;
+
+        let (_mock, path) = MockLogind::start(dir.path(), vec![text_only, unusable]);
+        let logind = Logind::connect_path(&path).await.unwrap();
+
+        // Only seat0 is shown on a vt, so other seats do not touch the host
+        let seat1 = SeatID("seat1".to_string());
+        let props = logind.query(&seat1).await.unwrap();
+        assert!(!props.can_graphical);
+        assert!(props.view == View::Seat(seat1));
+
+        assert!(logind.query(&SeatID("seat2".to_string())).await.is_err());
+        assert!(logind.query(&SeatID("seat3".to_string())).await.is_err());
+    }
 }
@@ -9,9 +9,13 @@ fn misconfiguration() {
     compile_error!("Select either 'logind' or 'seatd' (or both) as a supported backend")
 }
 
-use anyhow::Result;
+use std::path::PathBuf;
+
+use anyhow::{Result, bail};
 use dyn_utils::dyn_trait;
-use envy::define_env;
+use envy::{EnvVariable, define_env};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info};
 
 define_env!(pub LibseatBackend(String) = "LIBSEAT_BACKEND");
 
@@ -59,14 +63,220 @@ pub trait SeatManager {
     async fn next_event(&mut self) -> Option<(SeatID, SeatEvent)>;
 
     // All this method does is enriches
-    async fn query(&mut self, id: &SeatID) -> Result<SeatProperties>;
+    // Queries do not borrow the manager mutably, so that seats can be queried concurrently
+    async fn query(&self, id: &SeatID) -> Result<SeatProperties>;
     // async fn swtich(&mut self, seat: SeatID, session: SessionID);
 }
 
 pub type SeatManagerObject = Box<dyn DynSeatManager>;
-pub async fn seat_manager() -> Result<SeatManagerObject> {
-    // #[cfg(feature = "seatd")]
-    todo!()
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SeatBackend {
+    Logind,
+    Seatd,
+}
+
+impl SeatBackend {
+    // In order of preference when probing
+    pub const ALL: [Self; 2] = [Self::Logind, Self::Seatd];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Logind => "logind",
+            Self::Seatd => "seatd",
+        }
+    }
+
+    pub fn compiled_in(self) -> bool {
+        match self {
+            Self::Logind => cfg!(feature = "logind"),
+            Self::Seatd => cfg!(feature = "seatd"),
+        }
+    }
+
+    // "builtin" and "noop" are valid for libseat, but not something we can manage seats with
+    fn from_libseat(backend: &LibseatBackend) -> Option<Self> {
+        Self::ALL.into_iter().find(|x| x.name() == backend.as_str())
+    }
+
+    async fn connect(self, endpoints: &Endpoints) -> Result<SeatManagerObject> {
+        match self {
+            #[cfg(feature = "logind")]
+            Self::Logind => Ok(Box::new(match &endpoints.logind {
+                Some(path) => logind::Logind::connect_path(path).await?,
+                None => logind::Logind::connect().await?,
+            })),
+            #[cfg(feature = "seatd")]
+            Self::Seatd => Ok(Box::new(match &endpoints.seatd {
+                Some(path) => seatd::Seatd::connect_path(path).await?,
+                None => seatd::Seatd::connect().await?,
+            })),
+            #[allow(unreachable_patterns)]
+            other => bail!("support for {} is not compiled in", other.name()),
+        }
+    }
+}
+
+// Where each backend listens, None for its usual place.
+// Only tests point them elsewhere.
+#[derive(Default)]
+struct Endpoints {
+    logind: Option<PathBuf>,
+    seatd: Option<PathBuf>,
+}
+
+pub async fn seat_manager(preferred: Option<SeatBackend>) -> Result<SeatManagerObject> {
+    let libseat = std::env::var(LibseatBackend::KEY).ok().map(LibseatBackend);
+    probe(preferred, libseat, &Endpoints::default()).await
+}
+
+async fn probe(
+    preferred: Option<SeatBackend>,
+    libseat: Option<LibseatBackend>,
+    endpoints: &Endpoints,
+) -> Result<SeatManagerObject> {
+    let from_env = libseat.and_then(|x| SeatBackend::from_libseat(&x));
+
+    // An explicit choice in the config is final, otherwise we probe
+    let candidates: Vec<SeatBackend> = match preferred {
+        Some(backend) => vec![backend],
+        None => from_env
+            .into_iter()
+            .chain(SeatBackend::ALL)
+            .filter(|x| x.compiled_in())
+            .collect(),
+    };
+
+    let mut tried = Vec::new();
+
+    for backend in candidates {
+        if tried.iter().any(|(x, _)| *x == backend) {
+            continue;
+        }
+
+        match backend.connect(endpoints).await {
+            Ok(manager) => {
+                info!("Using {} as the seat backend", backend.name());
+                return Ok(manager);
+            }
+            Err(e) => {
+                debug!("Seat backend {} is not available: {e:?}", backend.name());
+                tried.push((backend, e));
+            }
+        }
+    }
+
+    let tried = tried
+        .iter()
+        .map(|(backend, e)| format!("\n  {}: {e:#}", backend.name()))
+        .collect::<String>();
+
+    bail!("No seat backend is available. Tried:{tried}")
+}
+
+#[cfg(all(test, feature = "logind", feature = "seatd"))]
+mod tests {
+    use std::path::Path;
+
+    use super::{
+        logind::tests::{MockLogind, MockSeat},
+        seatd::tests::{Behaviour, fake_seatd},
+        *,
+    };
+
+    // Backends that are down have nothing listening at their socket
+    fn endpoints(dir: &Path, logind: bool, seatd: bool) -> Endpoints {
+        let logind = match logind {
+            // seatd only ever has seat0, so this tells them apart
+            true => MockLogind::start(dir, vec![MockSeat::new("seat1")]).1,
+            false => dir.join("missing-logind"),
+        };
+
+        let seatd = match seatd {
+            true => fake_seatd(dir, Behaviour::Pong),
+            false => dir.join("missing-seatd"),
+        };
+
+        Endpoints {
+            logind: Some(logind),
+            seatd: Some(seatd),
+        }
+    }
+
+    async fn seats(
+        preferred: Option<SeatBackend>,
+        libseat: Option<&str>,
+        endpoints: &Endpoints,
+    ) -> Result<Vec<String>> {
+        let libseat = libseat.map(|x| LibseatBackend(x.to_string()));
+        let mut manager = probe(preferred, libseat, endpoints).await?;
+
+        let seats = manager.list_seats().await;
+        Ok(seats.into_iter().map(|x| x.as_str().to_string()).collect())
+    }
+
+    #[tokio::test]
+    async fn prefers_logind() {
+        let dir = tempfile::tempdir().unwrap();
+        let endpoints = endpoints(dir.path(), true, true);
+
+        assert_eq!(seats(None, None, &endpoints).await.unwrap(), ["seat1"]);
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_seatd() {
+        let dir = tempfile::tempdir().unwrap();
+        let endpoints = endpoints(dir.path(), false, true);
+
+        assert_eq!(seats(None, None, &endpoints).await.unwrap(), ["seat0"]);
+    }
+
+    #[tokio::test]
+    async fn tries_libseat_backend_first() {
+        let dir = tempfile::tempdir().unwrap();
+        let endpoints = endpoints(dir.path(), true, true);
+
+        let picked = seats(None, Some("seatd"), &endpoints).await;
+        assert_eq!(picked.unwrap(), ["seat0"]);
+
+        // Valid for libseat, but nothing flint manages seats with
+        let picked = seats(None, Some("builtin"), &endpoints).await;
+        assert_eq!(picked.unwrap(), ["seat1"]);
+    }
+
+    #[tokio::test]
+    async fn config_choice_is_final() {
+        let dir = tempfile::tempdir().unwrap();
+        let endpoints = endpoints(dir.path(), false, true);
+
+        let picked = seats(Some(SeatBackend::Seatd), Some("logind"), &endpoints).await;
+        assert_eq!(picked.unwrap(), ["seat0"]);
+
+        // No probing for another one if it is down
+        let error = seats(Some(SeatBackend::Logind), None, &endpoints)
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("logind:"));
+        assert!(!error.contains("seatd:"));
+    }
+
+    #[tokio::test]
+    async fn names_every_backend_tried() {
+        let dir = tempfile::tempdir().unwrap();
+        let endpoints = endpoints(dir.path(), false, false);
+
+        let error = seats(None, Some("seatd"), &endpoints)
+            .await
+            .unwrap_err()
+            .to_string();
+
+        assert!(error.starts_with("No seat backend is available. Tried:"));
+        // Once each, even though LIBSEAT_BACKEND named seatd as well
+        assert_eq!(error.matches("\n  logind: ").count(), 1);
+        assert_eq!(error.matches("\n  seatd: ").count(), 1);
+    }
 }
 
 // Views are purely flint's abstraction over seats:
@@ -75,14 +285,18 @@ pub async fn seat_manager() -> Result<SeatManagerObject> {
 // The main difference is that they never pass "seat0" around
 // as a special case, which results in (subjectively) better code
 pub mod view {
-    use anyhow::Context;
+    use anyhow::{Context, Result};
     use tracing::warn;
 
     use crate::{
         seat::SeatID,
-        utils::{tty::VtNumber, warn::WarnExt},
+        utils::{
+            tty::{VT, VtNumber},
+            warn::WarnExt,
+        },
     };
 
+    #[derive(Clone, PartialEq, Eq)]
     pub enum View {
         Vt(VtNumber),
         Seat(SeatID),
@@ -96,6 +310,16 @@ pub mod view {
             }
         }
 
+        // seat0 is viewed through a vt of its own, one that nobody else uses (i.e. not a getty),
+        // every other seat is a view of its own
+        pub fn for_seat(id: SeatID) -> Result<Self> {
+            if !id.is_seat0() {
+                return Ok(Self::Seat(id));
+            }
+
+            Ok(Self::Vt(VT::first_free()?))
+        }
+
         pub fn from_env(env: &impl envy::Get) -> Option<Self> {
             let vt = env
                 .maybe_get::<VtNumber>()
@@ -0,0 +1,303 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, bail, ensure};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::UnixStream,
+};
+use tracing::debug;
+
+use super::{SeatEvent, SeatID, SeatManager, SeatProperties, view::View};
+
+const DEFAULT_SOCKET_PATH: &str = "/run/seatd.sock";
+
+// A minimal subset of the libseat <-> seatd wire protocol.
+// ref: seatd/include/protocol.h
+mod proto {
+    use anyhow::{Result, bail};
+
+    const fn server(opcode: u16) -> u16 {
+        opcode + (1 << 15)
+    }
+
+    pub const CLIENT_PING: u16 = 7;
+
+    pub const SERVER_PONG: u16 = server(7);
+    pub const SERVER_ERROR: u16 = server(0x7FFF);
+
+    pub const HEADER_SIZE: usize = 4;
+
+    // seatd speaks in native endianness, as both ends live on the same machine
+    pub struct Header {
+        pub opcode: u16,
+        pub size: u16,
+    }
+
+    impl Header {
+        pub fn serialize(&self) -> [u8; HEADER_SIZE] {
+            let mut buf = [0; HEADER_SIZE];
+            buf[..2].copy_from_slice(&self.opcode.to_ne_bytes());
+            buf[2..].copy_from_slice(&self.size.to_ne_bytes());
+            buf
+        }
+
+        pub fn deserialize(buf: [u8; HEADER_SIZE]) -> Self {
+            Self {
+                opcode: u16::from_ne_bytes([buf[0], buf[1]]),
+                size: u16::from_ne_bytes([buf[2], buf[3]]),
+            }
+        }
+    }
+
+    pub fn error_code(body: &[u8]) -> Result<i32> {
+        match body.first_chunk::<4>() {
+            Some(raw) => Ok(i32::from_ne_bytes(*raw)),
+            None => bail!("Truncated error message"),
+        }
+    }
+}
+
+pub struct Seatd {
+    path: PathBuf,
+    stream: UnixStream,
+    // seatd closes the connection when it goes away,
+    // after which the only seat it manages is gone too
+    closed: bool,
+}
+
+impl Seatd {
+    pub fn socket_path() -> PathBuf {
+        std::env::var_os("SEATD_SOCK")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| DEFAULT_SOCKET_PATH.into())
+    }
+
+    pub async fn connect() -> Result<Self> {
+        Self::connect_path(&Self::socket_path()).await
+    }
+
+    pub async fn connect_path(path: &Path) -> Result<Self> {
+        let stream = UnixStream::connect(path)
+            .await
+            .with_context(|| format!("Failed to connect to seatd at {path:?}"))?;
+
+        let mut seatd = Self {
+            path: path.to_owned(),
+            stream,
+            closed: false,
+        };
+
+        seatd.ping().await.context("seatd did not answer a ping")?;
+        Ok(seatd)
+    }
+
+    async fn send(&mut self, opcode: u16) -> Result<()> {
+        let header = proto::Header { opcode, size: 0 };
+        self.stream.write_all(&header.serialize()).await?;
+        self.stream.flush().await?;
+        Ok(())
+    }
+
+    // Returns None if the server closed the connection
+    async fn recv(&mut self) -> Result<Option<(u16, Vec<u8>)>> {
+        let mut header = [0; proto::HEADER_SIZE];
+
+        match self.stream.read_exact(&mut header).await {
+            Ok(_) => (),
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+
+        let header = proto::Header::deserialize(header);
+
+        let mut body = vec![0; header.size.into()];
+        self.stream
+            .read_exact(&mut body)
+            .await
+            .context("Truncated message from seatd")?;
+
+        Ok(Some((header.opcode, body)))
+    }
+
+    async fn ping(&mut self) -> Result<()> {
+        self.send(proto::CLIENT_PING).await?;
+
+        match self.recv().await? {
+            Some((proto::SERVER_PONG, _)) => Ok(()),
+            Some((proto::SERVER_ERROR, body)) => {
+                bail!("seatd returned error {}", proto::error_code(&body)?)
+            }
+            Some((opcode, _)) => bail!("Unexpected opcode from seatd: {opcode:#x}"),
+            None => bail!("seatd closed the connection"),
+        }
+    }
+}
+
+impl SeatManager for Seatd {
+    fn libseat_backend() -> &'static str {
+        "seatd"
+    }
+
+    async fn list_seats(&mut self) -> Vec<SeatID> {
+        // seatd only ever manages a single seat
+        match self.closed {
+            true => Vec::new(),
+            false => vec![SeatID::seat0()],
+        }
+    }
+
+    async fn next_event(&mut self) -> Option<(SeatID, SeatEvent)> {
+        if self.closed {
+            return None;
+        }
+
+        // We never open the seat ourselves, so seatd has nothing to tell us.
+        // Anything other than the connection closing is ignored.
+        loop {
+            match self.recv().await {
+                Ok(Some((opcode, _))) => debug!("Ignoring unsolicited seatd message {opcode:#x}"),
+                Ok(None) | Err(_) => break,
+            }
+        }
+
+        self.closed = true;
+        Some((SeatID::seat0(), SeatEvent::Removed))
+    }
+
+    async fn query(&self, id: &SeatID) -> Result<SeatProperties> {
+        ensure!(!self.closed, "seatd connection is closed");
+        ensure!(id.is_seat0(), "seatd does not manage seat {}", id.as_str());
+
+        // A separate connection, as the main one may be waiting for events
+        Self::connect_path(&self.path)
+            .await
+            .context("seatd is not responding")?;
+
+        Ok(SeatProperties {
+            view: View::for_seat(id.clone())?,
+            // seatd hands out DRM and input devices, which is all a graphical session needs
+            can_graphical: true,
+        })
+    }
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use std::path::Path;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{UnixListener, UnixStream},
+    };
+
+    use super::*;
+
+    #[derive(Clone, Copy)]
+    pub(crate) enum Behaviour {
+        // Answer every ping
+        Pong,
+        // Answer the first ping, then hang up
+        PongOnce,
+        // Answer every request with an error
+        Refuse,
+    }
+
+    async fn reply(stream: &mut UnixStream, opcode: u16, body: &[u8]) {
+        let header = proto::Header {
+            opcode,
+            size: body.len() as u16,
+        };
+        stream.write_all(&header.serialize()).await.unwrap();
+        stream.write_all(body).await.unwrap();
+    }
+
+    async fn serve(mut stream: UnixStream, behaviour: Behaviour) {
+        let mut header = [0; proto::HEADER_SIZE];
+
+        while stream.read_exact(&mut header).await.is_ok() {
+            let header = proto::Header::deserialize(header);
+            assert_eq!(header.opcode, proto::CLIENT_PING);
+
+            match behaviour {
+                Behaviour::Refuse => {
+                    reply(&mut stream, proto::SERVER_ERROR, &13i32.to_ne_bytes()).await
+                }
+                Behaviour::Pong => reply(&mut stream, proto::SERVER_PONG, &[]).await,
+                Behaviour::PongOnce => {
+                    reply(&mut stream, proto::SERVER_PONG, &[]).await;
+                    return;
+                }
+            }
+        }
+    }
+
+    // A stand-in for seatd, listening in a temporary directory
+    pub(crate) fn fake_seatd(dir: &Path, behaviour: Behaviour) -> PathBuf {
+        let path = dir.join("seatd.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(serve(stream, behaviour));
+            }
+        });
+
+        path
+    }
+
+    #[tokio::test]
+    async fn lists_seat0() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = fake_seatd(dir.path(), Behaviour::Pong);
+
+        let mut seatd = Seatd::connect_path(&path).await.unwrap();
+
+        assert_eq!(Seatd::libseat_backend(), "seatd");
+        assert!(seatd.list_seats().await == vec![SeatID::seat0()]);
+    }
+
+    #[tokio::test]
+    async fn removes_seat_when_seatd_goes_away() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = fake_seatd(dir.path(), Behaviour::PongOnce);
+
+        let mut seatd = Seatd::connect_path(&path).await.unwrap();
+
+        let (id, event) = seatd.next_event().await.unwrap();
+        assert!(id.is_seat0());
+        assert!(matches!(event, SeatEvent::Removed));
+
+        assert!(seatd.list_seats().await.is_empty());
+        assert!(seatd.next_event().await.is_none());
+        assert!(seatd.query(&SeatID::seat0()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn refused_ping_fails_to_connect() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = fake_seatd(dir.path(), Behaviour::Refuse);
+
+        assert!(Seatd::connect_path(&path).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn query_rejects_other_seats() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = fake_seatd(dir.path(), Behaviour::Pong);
+
+        let seatd = Seatd::connect_path(&path).await.unwrap();
+        let other = SeatID("seat1".to_string());
+
+        assert!(seatd.query(&other).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn missing_socket_fails_to_connect() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(
+            Seatd::connect_path(&dir.path().join("missing.sock"))
+                .await
+                .is_err()
+        );
+    }
+}
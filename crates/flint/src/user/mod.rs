@@ -6,8 +6,10 @@ mod nss;
 
 use std::ffi::c_uint;
 
-use anyhow::Result;
+use anyhow::{Context, Result, bail};
 use dyn_utils::{dyn_object, dyn_trait};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
 
 pub type Uid = c_uint;
 pub type Gid = c_uint;
@@ -27,3 +29,77 @@ pub trait UserProvider {
     #[dyn_trait(maybe_sync)]
     async fn resolve(&mut self, name: &str) -> Result<Option<UserMeta>>;
 }
+
+pub type UserProviderObject = Box<dyn DynUserProvider>;
+
+// The configured provider chain, the first provider that knows a user wins
+pub struct UserProviders {
+    chain: Vec<(ProviderKind, UserProviderObject)>,
+}
+
+impl UserProviders {
+    // Providers which fail to connect are skipped
+    pub async fn connect(kinds: &[ProviderKind]) -> Self {
+        let mut chain = Vec::new();
+
+        for &kind in kinds {
+            match kind.connect().await {
+                Ok(provider) => chain.push((kind, provider)),
+                Err(e) => warn!("Skipping user provider {}: {e:?}", kind.name()),
+            }
+        }
+
+        Self { chain }
+    }
+
+    pub async fn resolve(&mut self, name: &str) -> Result<UserMeta> {
+        for (kind, provider) in &mut self.chain {
+            let user = provider.resolve(name).await.with_context(|| {
+                format!("User provider {} failed to resolve {name}", kind.name())
+            })?;
+
+            if let Some(user) = user {
+                return Ok(user);
+            }
+        }
+
+        bail!("User {name} is not known to any user provider")
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ProviderKind {
+    Userdb,
+    Nss,
+}
+
+impl ProviderKind {
+    // In order of preference
+    pub const ALL: [Self; 2] = [Self::Userdb, Self::Nss];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Userdb => "userdb",
+            Self::Nss => "nss",
+        }
+    }
+
+    pub fn compiled_in(self) -> bool {
+        match self {
+            Self::Userdb => cfg!(feature = "userdb"),
+            Self::Nss => cfg!(feature = "nss"),
+        }
+    }
+
+    pub async fn connect(self) -> Result<UserProviderObject> {
+        match self {
+            #[cfg(feature = "userdb")]
+            Self::Userdb => Ok(Box::new(userdb::UserDB::connect().await?)),
+            #[cfg(feature = "nss")]
+            Self::Nss => Ok(Box::new(nss::NSS)),
+            #[allow(unreachable_patterns)]
+            other => bail!("support for {} is not compiled in", other.name()),
+        }
+    }
+}
@@ -1,3 +1,4 @@
+pub mod shutdown;
 pub mod tty;
 // pub mod plymouth;
 
@@ -24,7 +25,7 @@ pub mod macros {
 
             $(
                 fn [<set_ $key:lower>](&mut self, value: $value) -> &mut Self {
-                    self.$key = self.$key.replace(value);
+                    self.$key = Some(value);
                     self
                 }
             )*
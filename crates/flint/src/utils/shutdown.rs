@@ -0,0 +1,156 @@
+use std::{future::Future, time::Duration};
+
+use tokio::task::JoinHandle;
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tracing::warn;
+
+// A node in the shutdown tree: global -> seat -> session.
+//
+// Cancelling a node cancels all of its descendants, but never its parent.
+// Every node waits for its own tasks, and a parent waits for its children,
+// each level bounded by its own timeout.
+#[derive(Clone)]
+pub struct Shutdown {
+    token: CancellationToken,
+    tasks: TaskTracker,
+    timeout: Duration,
+}
+
+impl Shutdown {
+    pub fn root(timeout: Duration) -> Self {
+        Self {
+            token: CancellationToken::new(),
+            tasks: TaskTracker::new(),
+            timeout,
+        }
+    }
+
+    pub fn child(&self, timeout: Duration) -> Self {
+        let child = Self {
+            token: self.token.child_token(),
+            tasks: TaskTracker::new(),
+            timeout,
+        };
+
+        // The parent only finishes draining once the child has
+        let node = child.clone();
+        self.tasks.spawn(async move {
+            node.token.cancelled().await;
+            node.drain().await;
+        });
+
+        child
+    }
+
+    pub fn spawn<F>(&self, task: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        self.tasks.spawn(task)
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.token.is_cancelled()
+    }
+
+    pub async fn cancelled(&self) {
+        self.token.cancelled().await
+    }
+
+    // Cancel this node and its descendants, without waiting for them
+    pub fn cancel(&self) {
+        self.token.cancel();
+    }
+
+    // Cancel this node and its descendants, and wait for them to finish
+    pub async fn shutdown(&self) {
+        self.cancel();
+        self.drain().await;
+    }
+
+    async fn drain(&self) {
+        self.tasks.close();
+
+        if tokio::time::timeout(self.timeout, self.tasks.wait())
+            .await
+            .is_err()
+        {
+            warn!(
+                "{} task(s) did not finish within {:?}, abandoning them",
+                self.tasks.len(),
+                self.timeout
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    };
+
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(1);
+
+    #[tokio::test]
+    async fn cancelling_a_child_leaves_the_parent() {
+        let parent = Shutdown::root(TIMEOUT);
+        let child = parent.child(TIMEOUT);
+        let grandchild = child.child(TIMEOUT);
+
+        child.cancel();
+
+        assert!(child.is_cancelled() && grandchild.is_cancelled());
+        assert!(!parent.is_cancelled());
+    }
+
+    #[tokio::test]
+    async fn cancelled_children_are_released() {
+        let parent = Shutdown::root(TIMEOUT);
+        parent.child(TIMEOUT).cancel();
+
+        // The task waiting for the child is all the parent tracks
+        tokio::time::timeout(TIMEOUT, async {
+            while !parent.tasks.is_empty() {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .expect("parent still tracks a cancelled child");
+    }
+
+    #[tokio::test]
+    async fn parent_waits_for_children() {
+        let parent = Shutdown::root(TIMEOUT);
+        let child = parent.child(TIMEOUT);
+
+        let done = Arc::new(AtomicBool::new(false));
+        let task = child.clone();
+        let finished = done.clone();
+        child.spawn(async move {
+            task.cancelled().await;
+            // Cleaning up takes a while
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            finished.store(true, Ordering::SeqCst);
+        });
+
+        parent.shutdown().await;
+        assert!(done.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn children_are_abandoned_after_their_timeout() {
+        let parent = Shutdown::root(TIMEOUT);
+        let child = parent.child(Duration::from_millis(50));
+        child.spawn(std::future::pending::<()>());
+
+        // Bounded by the timeout of the child, not that of the parent
+        tokio::time::timeout(TIMEOUT / 2, parent.shutdown())
+            .await
+            .expect("parent waited past the timeout of its child");
+    }
+}
@@ -1,15 +1,24 @@
 use std::{
+    ffi::{c_int, c_uint},
+    io::ErrorKind,
     num::ParseIntError,
     ops::Deref,
     os::fd::{AsFd, BorrowedFd, OwnedFd},
+    sync::Mutex,
 };
 
 use anyhow::{Context, Result, bail, ensure};
 use envy::{EnvVariable, parse::EnvironmentParse};
 use rustix::{
     fs::{self, OFlags},
-    io, ioctl, stdio,
+    io, ioctl,
+    process::{Gid, Uid},
+    stdio,
+    termios::{self, LocalModes, OptionalActions, Termios},
 };
+use tokio::io::{Interest, unix::AsyncFd};
+
+use crate::utils::warn::WarnExt;
 
 // TODO: for cases when we immediately set as ctty via ioctl, not setting NOCTTY is an optimization.
 fn open_dev(name: &str) -> io::Result<OwnedFd> {
@@ -49,12 +58,77 @@ impl<F: AsFd> Terminal<F> {
         Ok(())
     }
 
+    pub fn write_all(&self, mut buf: &[u8]) -> io::Result<()> {
+        while !buf.is_empty() {
+            let written = rustix::io::write(&self.fd, buf)?;
+            buf = &buf[written..];
+        }
+        Ok(())
+    }
+
+    // Resolves once a full line is entered.
+    // The terminal is expected to be in canonical mode, i.e. the kernel handles editing,
+    // and only hands out complete lines. So dropping this before then consumes no input,
+    // and the next read gets whatever was typed meanwhile.
+    pub async fn read_line(&self, echo: bool) -> std::io::Result<String> {
+        let reading = Reading::start(self.fd.as_fd(), echo)?;
+        let fd = AsyncFd::with_interest(self.fd.as_fd(), Interest::READABLE)?;
+
+        let mut line = Vec::new();
+        loop {
+            let mut ready = fd.readable().await?;
+
+            // A line is at most MAX_CANON (4096) bytes
+            let mut buffer = [0; 4096];
+            let read = ready.try_io(|fd| Ok(io::read(fd.get_ref(), &mut buffer)?));
+
+            match read {
+                Ok(Ok(0)) => break,
+                Ok(Ok(n)) => {
+                    line.extend_from_slice(&buffer[..n]);
+                    if line.last() == Some(&b'\n') {
+                        line.pop();
+                        break;
+                    }
+                }
+                Ok(Err(e)) if e.kind() == ErrorKind::Interrupted => continue,
+                Ok(Err(e)) => return Err(e),
+                // Readiness was stale, try_io already cleared it
+                Err(_) => continue,
+            }
+        }
+
+        drop(reading);
+        if !echo {
+            // The newline was not echoed either
+            self.write_all(b"\n")?;
+        }
+
+        String::from_utf8(line).map_err(|_| io::Errno::ILSEQ.into())
+    }
+
+    pub fn try_clone(&self) -> io::Result<Terminal<OwnedFd>> {
+        Ok(Terminal {
+            fd: io::dup(&self.fd)?,
+            number: self.number,
+        })
+    }
+
     pub fn set_as_ctty(&self) -> io::Result<()> {
         type I = ioctl::IntegerSetter<0x540E>;
         // Safety: self.fd is a terminal
         unsafe { ioctl::ioctl(&self.fd, I::new_usize(1)) }
     }
 
+    // Like login(1), so that the user can open the terminal by path.
+    // It is taken back once the result is dropped.
+    pub fn lend(self, uid: c_uint, gid: c_uint) -> io::Result<Lent<F>> {
+        let (uid, gid) = (Uid::from_raw(uid), Gid::from_raw(gid));
+        fs::fchown(&self.fd, Some(uid), Some(gid))?;
+        fs::fchmod(&self.fd, fs::Mode::from_raw_mode(0o620))?;
+        Ok(Lent(self))
+    }
+
     pub fn set_as_stdio(&self) -> io::Result<()> {
         let fd = self.fd.as_fd();
         stdio::dup2_stdin(&fd)?;
@@ -91,6 +165,7 @@ impl Terminal<BorrowedFd<'static>> {
     }
 }
 
+#[derive(Clone, Copy, PartialEq, Eq)]
 pub struct VtNumber(u8);
 
 impl Deref for VtNumber {
@@ -130,6 +205,72 @@ pub struct VT<F> {
     terminal: Terminal<F>,
 }
 
+// A terminal some user owns for now, see Terminal::lend.
+// Otherwise they could still open it once they are gone, and read what the next user types.
+pub struct Lent<F: AsFd>(Terminal<F>);
+
+impl<F: AsFd> Lent<F> {
+    fn reclaim(&self) -> io::Result<()> {
+        // The mode first, which works even if we could not chown
+        fs::fchmod(&self.0.fd, fs::Mode::from_raw_mode(0o600))?;
+        fs::fchown(&self.0.fd, Some(Uid::ROOT), Some(Gid::ROOT))
+    }
+}
+
+impl<F: AsFd> Deref for Lent<F> {
+    type Target = Terminal<F>;
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+// How a terminal is set up while a line is read from it: non-blocking,
+// and without echo for secrets. Restored once reading is done, or cancelled.
+struct Reading<'a> {
+    fd: BorrowedFd<'a>,
+    flags: OFlags,
+    termios: Termios,
+}
+
+impl<'a> Reading<'a> {
+    fn start(fd: BorrowedFd<'a>, echo: bool) -> io::Result<Self> {
+        let flags = fs::fcntl_getfl(fd)?;
+        let termios = termios::tcgetattr(fd)?;
+        let reading = Self { fd, flags, termios };
+
+        fs::fcntl_setfl(fd, flags | OFlags::NONBLOCK)?;
+
+        if !echo {
+            let mut silent = reading.termios.clone();
+            silent.local_modes.remove(LocalModes::ECHO);
+            termios::tcsetattr(fd, OptionalActions::Now, &silent)?;
+        }
+
+        Ok(reading)
+    }
+
+    fn restore(&self) -> io::Result<()> {
+        termios::tcsetattr(self.fd, OptionalActions::Now, &self.termios)?;
+        fs::fcntl_setfl(self.fd, self.flags)
+    }
+}
+
+impl Drop for Reading<'_> {
+    fn drop(&mut self) {
+        self.restore()
+            .context("Failed to restore the terminal after reading")
+            .warn();
+    }
+}
+
+impl<F: AsFd> Drop for Lent<F> {
+    fn drop(&mut self) {
+        self.reclaim()
+            .context("Failed to take the terminal back from its user")
+            .warn();
+    }
+}
+
 impl<F> Deref for VT<F> {
     type Target = Terminal<F>;
     fn deref(&self) -> &Self::Target {
@@ -162,6 +303,21 @@ impl VT<OwnedFd> {
         let terminal = Terminal::try_from_fd(fd)?;
         Ok(Self { terminal })
     }
+
+    // The first vt nobody has open, so we never take over i.e. a getty
+    pub fn first_free() -> Result<VtNumber> {
+        let fd = open_dev("tty0").context("Failed to open current VT")?;
+
+        type IoOpenQuery = ioctl::Getter<0x5600, c_int>;
+        let number = unsafe { ioctl::ioctl(&fd, IoOpenQuery::new()) }
+            .context("Failed to query for a free vt")?;
+
+        // -1 if all are taken
+        u8::try_from(number)
+            .ok()
+            .and_then(VtNumber::new)
+            .context("No free vt is available")
+    }
 }
 
 macro_rules! vt_property {
@@ -262,8 +418,74 @@ struct SwitchVtTarget {
     mode: Mode,
 }
 
+// Holders of SwitchLock, switching is locked for as long as there is one
+static SWITCH_LOCKS: Mutex<usize> = Mutex::new(0);
+
+fn set_switching(allowed: bool) -> io::Result<()> {
+    type IoLockSwitch = ioctl::NoArg<0x560B>;
+    type IoUnlockSwitch = ioctl::NoArg<0x560C>;
+
+    let fd = open_dev("tty0")?;
+
+    unsafe {
+        match allowed {
+            true => ioctl::ioctl(&fd, IoUnlockSwitch::new()),
+            false => ioctl::ioctl(&fd, IoLockSwitch::new()),
+        }
+    }
+}
+
+// Keeps users from switching vts (i.e. with Ctrl+Alt+Fn) for as long as it is held.
+// flint itself can still switch, see VT::activate.
+pub struct SwitchLock(());
+
+impl SwitchLock {
+    pub fn acquire() -> Result<Self> {
+        let mut locks = SWITCH_LOCKS.lock().unwrap();
+
+        if *locks == 0 {
+            set_switching(false).context("Failed to lock vt switching")?;
+        }
+
+        *locks += 1;
+        Ok(Self(()))
+    }
+}
+
+impl Drop for SwitchLock {
+    fn drop(&mut self) {
+        let mut locks = SWITCH_LOCKS.lock().unwrap();
+        *locks -= 1;
+
+        if *locks == 0 {
+            set_switching(true)
+                .context("Failed to unlock vt switching")
+                .warn();
+        }
+    }
+}
+
 impl<F: AsFd> VT<F> {
+    // If switching is locked, it is lifted for just as long as this takes,
+    // as the kernel does not make an exception for us
     pub fn activate(&self) -> io::Result<()> {
+        let locks = SWITCH_LOCKS.lock().unwrap();
+        let locked = *locks > 0;
+
+        if locked {
+            set_switching(true)?;
+        }
+
+        let activated = self.switch_to();
+
+        if locked {
+            set_switching(false)?;
+        }
+
+        activated
+    }
+
+    fn switch_to(&self) -> io::Result<()> {
         let target = SwitchVtTarget {
             number: self.number as _,
 
@@ -283,3 +505,69 @@ impl<F: AsFd> VT<F> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use rustix::process;
+
+    use super::*;
+
+    // The other end plays the user, and Terminal gets the pseudo terminal itself
+    fn pty() -> (OwnedFd, Terminal<OwnedFd>, String) {
+        let user = open_dev("ptmx").unwrap();
+
+        type IoUnlock = ioctl::Setter<0x40045431, c_int>;
+        type IoNumber = ioctl::Getter<0x80045430, c_uint>;
+
+        let number = unsafe {
+            ioctl::ioctl(&user, IoUnlock::new(0)).unwrap();
+            ioctl::ioctl(&user, IoNumber::new()).unwrap()
+        };
+
+        let name = format!("pts/{number}");
+        let fd = open_dev(&name).unwrap();
+
+        (user, Terminal { fd, number: 0 }, format!("/dev/{name}"))
+    }
+
+    #[test]
+    fn takes_lent_terminals_back() {
+        let (_user, terminal, path) = pty();
+        let mode = || fs::stat(&path).unwrap().st_mode & 0o777;
+
+        let (uid, gid) = (process::geteuid(), process::getegid());
+        let lent = terminal.lend(uid.as_raw(), gid.as_raw()).unwrap();
+        assert_eq!(mode(), 0o620);
+
+        drop(lent);
+        assert_eq!(mode(), 0o600);
+
+        // Only root can give it to root
+        if uid.is_root() {
+            assert_eq!(fs::stat(&path).unwrap().st_uid, 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn cancelled_reads_leave_input_alone() {
+        let (user, terminal, _) = pty();
+
+        // Gives up before the user is done
+        let pending = tokio::time::timeout(Duration::from_millis(50), terminal.read_line(false));
+        assert!(pending.await.is_err());
+
+        let modes = termios::tcgetattr(&terminal.fd).unwrap().local_modes;
+        assert!(modes.contains(LocalModes::ECHO));
+        assert!(
+            !fs::fcntl_getfl(&terminal.fd)
+                .unwrap()
+                .contains(OFlags::NONBLOCK)
+        );
+
+        // So the next prompt gets the whole line
+        io::write(&user, b"secret\n").unwrap();
+        assert_eq!(terminal.read_line(true).await.unwrap(), "secret");
+    }
+}
@@ -0,0 +1,158 @@
+// A conversation carried over channels.
+// PAM blocks until it gets an answer, so it has to run on its own (blocking) thread,
+// while the answers come from async code, i.e. a greeter. See spawn.
+use tokio::{
+    sync::{mpsc, oneshot},
+    task::{self, JoinHandle},
+};
+
+use crate::converse::{ConversationError, MessageLevel, PamDisplay};
+
+pub enum Request {
+    Prompt {
+        text: String,
+        show: bool,
+        reply: oneshot::Sender<String>,
+    },
+    Message {
+        text: String,
+        level: MessageLevel,
+    },
+}
+
+// The PAM side, pass this to Pam::new.
+// Only spawn hands these out, so that it never ends up in async code.
+pub struct ChannelDisplay {
+    requests: mpsc::UnboundedSender<Request>,
+}
+
+// The async side.
+// Dropping it cancels the conversation, PAM then fails with CONV_ERR.
+pub struct Conversation {
+    requests: mpsc::UnboundedReceiver<Request>,
+}
+
+fn channel() -> (ChannelDisplay, Conversation) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    (
+        ChannelDisplay { requests: tx },
+        Conversation { requests: rx },
+    )
+}
+
+// Runs f on a blocking thread, where PAM is free to wait for answers.
+// Answer through the Conversation meanwhile, then await the handle for what f returned.
+pub fn spawn<T, F>(f: F) -> (JoinHandle<T>, Conversation)
+where
+    T: Send + 'static,
+    F: FnOnce(ChannelDisplay) -> T + Send + 'static,
+{
+    let (display, conversation) = channel();
+    (task::spawn_blocking(move || f(display)), conversation)
+}
+
+impl PamDisplay for ChannelDisplay {
+    fn prompt(&self, text: &str, show: bool) -> Result<String, ConversationError> {
+        let (reply, response) = oneshot::channel();
+
+        self.requests.send(Request::Prompt {
+            text: text.to_string(),
+            show,
+            reply,
+        })?;
+
+        // Fine to block, as this runs on the thread of spawn
+        Ok(response.blocking_recv()?)
+    }
+
+    fn message(&self, text: &str, level: MessageLevel) -> Result<(), ConversationError> {
+        self.requests.send(Request::Message {
+            text: text.to_string(),
+            level,
+        })?;
+        Ok(())
+    }
+}
+
+impl Conversation {
+    // None once PAM is done, i.e. the ChannelDisplay was dropped
+    pub async fn next(&mut self) -> Option<Request> {
+        self.requests.recv().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{PamReturnCode, stub::StubService};
+
+    // Answers prompts in order, returns what was asked
+    async fn answer(mut conversation: Conversation, answers: &[&str]) -> Vec<String> {
+        let mut answers = answers.iter();
+        let mut asked = Vec::new();
+
+        while let Some(request) = conversation.next().await {
+            if let Request::Prompt { text, reply, .. } = request {
+                asked.push(text);
+                let _ = reply.send(answers.next().unwrap().to_string());
+            }
+        }
+
+        asked
+    }
+
+    fn authenticate(
+        service: StubService,
+        username: Option<&'static str>,
+    ) -> (JoinHandle<crate::Result<String>>, Conversation) {
+        spawn(move |display| {
+            let mut pam = service.start(Some(display), username)?;
+            pam.authenticate(false)?;
+            pam.get_username()
+        })
+    }
+
+    #[tokio::test]
+    async fn authenticates_through_the_conversation() {
+        let service = StubService::new("password=hunter2");
+
+        let (auth, conversation) = authenticate(service, Some("alice"));
+        let asked = answer(conversation, &["hunter2"]).await;
+
+        assert_eq!(auth.await.unwrap().unwrap(), "alice");
+        assert_eq!(asked.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn prompts_for_the_username() {
+        let service = StubService::new("lowercase password=hunter2");
+
+        let (auth, conversation) = authenticate(service, None);
+        let asked = answer(conversation, &["Alice", "hunter2"]).await;
+
+        assert_eq!(auth.await.unwrap().unwrap(), "alice");
+        assert_eq!(asked.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn fails_on_a_wrong_answer() {
+        let service = StubService::new("password=hunter2");
+
+        let (auth, conversation) = authenticate(service, Some("alice"));
+        answer(conversation, &["hunter3"]).await;
+
+        let error = auth.await.unwrap().unwrap_err();
+        assert_eq!(error.code(), Some(PamReturnCode::AUTH_ERR));
+    }
+
+    #[tokio::test]
+    async fn gives_up_without_a_conversation() {
+        let service = StubService::new("password=hunter2");
+
+        // Nobody is there to answer
+        let (auth, conversation) = authenticate(service, Some("alice"));
+        drop(conversation);
+
+        assert!(auth.await.unwrap().is_err());
+    }
+}
@@ -1,6 +1,6 @@
 use libc::{c_char, c_int, c_void, calloc, free, size_t};
-use pam_sys::{PamConversation, PamMessage, PamMessageStyle, PamResponse, PamReturnCode};
-use std::{error::Error, ffi::CStr, mem, pin::Pin, ptr};
+use pam_sys::{PamConversation, PamMessage, PamResponse, PamReturnCode};
+use std::{error::Error, ffi::CStr, marker::PhantomPinned, mem, pin::Pin, ptr};
 
 pub enum MessageLevel {
     Error,
@@ -10,6 +10,28 @@ pub enum MessageLevel {
 pub trait PamDisplay {
     fn prompt(&self, text: &str, show: bool) -> Result<String, ConversationError>;
     fn message(&self, text: &str, level: MessageLevel) -> Result<(), ConversationError>;
+
+    // Linux-PAM binary prompts, used by i.e. smartcard and U2F modules.
+    // Both ways, a packet is a control byte and opaque data, the meaning of which is up to the module.
+    // Refused by default, which fails the conversation.
+    fn binary_prompt(&self, control: u8, data: &[u8]) -> Result<(u8, Vec<u8>), ConversationError> {
+        let _ = (control, data);
+        Err(ConversationError)
+    }
+}
+
+impl<T: PamDisplay + ?Sized> PamDisplay for Box<T> {
+    fn prompt(&self, text: &str, show: bool) -> Result<String, ConversationError> {
+        (**self).prompt(text, show)
+    }
+
+    fn message(&self, text: &str, level: MessageLevel) -> Result<(), ConversationError> {
+        (**self).message(text, level)
+    }
+
+    fn binary_prompt(&self, control: u8, data: &[u8]) -> Result<(u8, Vec<u8>), ConversationError> {
+        (**self).binary_prompt(control, data)
+    }
 }
 
 pub struct ConversationError;
@@ -28,6 +50,71 @@ impl Into<PamReturnCode> for ConversationError {
     }
 }
 
+// Message styles as PAM passes them.
+// pam_sys converts unknown values to PamMessageStyle unchecked, so we match on the raw value instead.
+mod style {
+    use libc::c_int;
+    use pam_sys::PamMessageStyle;
+
+    pub const PROMPT_ECHO_OFF: c_int = PamMessageStyle::PROMPT_ECHO_OFF as c_int;
+    pub const PROMPT_ECHO_ON: c_int = PamMessageStyle::PROMPT_ECHO_ON as c_int;
+    pub const ERROR_MSG: c_int = PamMessageStyle::ERROR_MSG as c_int;
+    pub const TEXT_INFO: c_int = PamMessageStyle::TEXT_INFO as c_int;
+
+    // Linux-PAM extensions
+    pub const RADIO_TYPE: c_int = 5;
+    pub const BINARY_PROMPT: c_int = 7;
+}
+
+// A Linux-PAM binary packet: a 4 byte big-endian length (of the whole packet), a control byte, then data
+// ref: Linux-PAM/libpamc/include/security/pam_client.h
+const BINARY_HEADER_SIZE: usize = 5;
+
+unsafe fn read_text<'a>(text: *const c_char) -> Result<&'a str, ConversationError> {
+    if text.is_null() {
+        return Err(ConversationError);
+    }
+
+    Ok(unsafe { CStr::from_ptr(text) }.to_str()?)
+}
+
+unsafe fn read_binary<'a>(packet: *const u8) -> Option<(u8, &'a [u8])> {
+    if packet.is_null() {
+        return None;
+    }
+
+    let header = unsafe { std::slice::from_raw_parts(packet, BINARY_HEADER_SIZE) };
+    let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+
+    let data_length = length.checked_sub(BINARY_HEADER_SIZE)?;
+    let data = unsafe { std::slice::from_raw_parts(packet.add(BINARY_HEADER_SIZE), data_length) };
+
+    Some((header[4], data))
+}
+
+unsafe fn to_binary(control: u8, mut data: Vec<u8>) -> Result<*mut c_char, ConversationError> {
+    let length = BINARY_HEADER_SIZE + data.len();
+    let header_length = u32::try_from(length)?.to_be_bytes();
+
+    unsafe {
+        let ptr = calloc(1, length) as *mut u8;
+        if ptr.is_null() {
+            panic!("unable to allocate binary response");
+        }
+
+        ptr::copy_nonoverlapping(header_length.as_ptr(), ptr, 4);
+        *ptr.add(4) = control;
+        ptr::copy_nonoverlapping(data.as_ptr(), ptr.add(BINARY_HEADER_SIZE), data.len());
+
+        // Same as for strings, this may be a secret
+        for byte in data.iter_mut() {
+            ptr::write_volatile(byte, 0);
+        }
+
+        Ok(ptr as *mut c_char)
+    }
+}
+
 fn zeroize_string(mut s: String) {
     let bytes = unsafe { s.as_bytes_mut() };
 
@@ -51,31 +138,52 @@ unsafe fn to_cstr(s: String) -> *mut c_char {
     }
 }
 
-pub struct PamConversationHandler<'a> {
-    pub display: Pin<Box<dyn PamDisplay + 'a>>,
+// PAM keeps a pointer to this until pam_end, so it is pinned and owned by Pam
+pub struct PamConversationHandler {
+    display: Box<dyn PamDisplay + Send>,
+    _pinned: PhantomPinned,
 }
 
-impl<'a> PamConversationHandler<'a> {
+impl PamConversationHandler {
+    pub fn new(display: impl PamDisplay + Send + 'static) -> Pin<Box<Self>> {
+        Box::pin(Self {
+            display: Box::new(display),
+            _pinned: PhantomPinned,
+        })
+    }
+
     fn handle(
         &self,
         message: &PamMessage,
         response_sender: &mut PamResponse,
     ) -> Result<(), ConversationError> {
-        let text = unsafe { CStr::from_ptr(message.msg) }.to_str()?;
+        // Only text styles carry a string, anything else may not even be NUL-terminated
+        let text = || unsafe { read_text(message.msg) };
 
-        match PamMessageStyle::from(message.msg_style) {
-            PamMessageStyle::PROMPT_ECHO_ON => {
-                let response = self.display.prompt(text, true)?;
+        match message.msg_style {
+            // A radio prompt is a question with a short visible answer, i.e. yes/no
+            style::PROMPT_ECHO_ON | style::RADIO_TYPE => {
+                let response = self.display.prompt(text()?, true)?;
                 response_sender.resp = unsafe { to_cstr(response) };
                 Ok(())
             }
-            PamMessageStyle::PROMPT_ECHO_OFF => {
-                let response = self.display.prompt(text, false)?;
+            style::PROMPT_ECHO_OFF => {
+                let response = self.display.prompt(text()?, false)?;
                 response_sender.resp = unsafe { to_cstr(response) };
                 Ok(())
             }
-            PamMessageStyle::ERROR_MSG => self.display.message(text, MessageLevel::Error),
-            PamMessageStyle::TEXT_INFO => self.display.message(text, MessageLevel::Info),
+            style::ERROR_MSG => self.display.message(text()?, MessageLevel::Error),
+            style::TEXT_INFO => self.display.message(text()?, MessageLevel::Info),
+            style::BINARY_PROMPT => {
+                let (control, data) =
+                    unsafe { read_binary(message.msg as *const u8) }.ok_or(ConversationError)?;
+
+                let (control, response) = self.display.binary_prompt(control, data)?;
+                response_sender.resp = unsafe { to_binary(control, response)? };
+                Ok(())
+            }
+            // Answering would mean guessing what the module wants
+            _unknown => Err(ConversationError),
         }
     }
 
@@ -129,21 +237,15 @@ impl<'a> PamConversationHandler<'a> {
         pam_ret as c_int
     }
 
-    pub fn pass_to_pam(mut self) -> PamConversation {
+    // The handler must outlive every PAM handle started with the returned conversation
+    pub fn conversation(self: Pin<&Self>) -> PamConversation {
         PamConversation {
             conv: Some(Self::converse),
-            data_ptr: &mut self as *mut PamConversationHandler as *mut c_void,
+            data_ptr: self.get_ref() as *const Self as *mut c_void,
         }
     }
 }
 
-pub fn new(display: impl PamDisplay) -> PamConversation {
-    PamConversationHandler {
-        display: Box::pin(display),
-    }
-    .pass_to_pam()
-}
-
 pub fn none() -> PamConversation {
     PamConversation {
         conv: None,
@@ -0,0 +1,65 @@
+use std::{ffi::CStr, fmt::Display};
+
+use pam_sys::{PamHandle as RawPamHandle, PamReturnCode, raw as sys};
+
+#[derive(Debug)]
+pub enum Error {
+    // A PAM call returned something other than SUCCESS
+    Call {
+        // Name of the failing function, i.e. "pam_authenticate"
+        operation: &'static str,
+        code: PamReturnCode,
+        // As returned by pam_strerror
+        message: String,
+    },
+
+    // PAM returned something we cannot use
+    InvalidData(&'static str),
+}
+
+impl Error {
+    pub(crate) fn call(
+        handle: *mut RawPamHandle,
+        operation: &'static str,
+        code: PamReturnCode,
+    ) -> Self {
+        // pam_strerror does not need a valid handle on Linux-PAM, so this is also fine after a failed start
+        let text = unsafe { sys::pam_strerror(handle, code as i32) };
+
+        let message = match text.is_null() {
+            true => "unknown error".to_string(),
+            false => unsafe { CStr::from_ptr(text) }
+                .to_string_lossy()
+                .into_owned(),
+        };
+
+        Self::Call {
+            operation,
+            code,
+            message,
+        }
+    }
+
+    // Callers mostly care about this, i.e. to tell AUTH_ERR from NEW_AUTHTOK_REQD
+    pub fn code(&self) -> Option<PamReturnCode> {
+        match self {
+            Self::Call { code, .. } => Some(*code),
+            Self::InvalidData(_) => None,
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Call {
+                operation,
+                code,
+                message,
+            } => write!(f, "{operation} failed: {message} ({code:?})"),
+            Self::InvalidData(what) => write!(f, "Invalid data from PAM: {what}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
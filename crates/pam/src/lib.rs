@@ -1,17 +1,27 @@
+pub mod bridge;
 mod converse;
-use anyhow::{Context, anyhow, bail};
-pub use converse::PamDisplay;
+pub use converse::{ConversationError, MessageLevel, PamDisplay};
+
+mod error;
+pub use error::Error;
 
 mod types;
 
+#[cfg(any(test, feature = "stub"))]
+pub mod stub;
+
 pub use types::{CredentialsOP, PamItemType};
 use types::{FlagsBuilder, flags};
 
-use pam_sys::{PamConversation, PamHandle as RawPamHandle, PamReturnCode, raw as sys};
+use converse::PamConversationHandler;
+use pam_sys::{PamConversation, PamHandle as RawPamHandle, raw as sys};
 
+#[cfg(any(test, feature = "stub"))]
+use std::{ffi::c_int, os::unix::ffi::OsStrExt, path::Path};
 use std::{
     ffi::{CStr, CString, OsString, c_void},
     os::{raw::c_char, unix::ffi::OsStringExt},
+    pin::Pin,
     ptr,
 };
 
@@ -22,20 +32,44 @@ pub unsafe fn getpwnam(name: *const c_char) -> *mut passwd {
     unsafe { libc::getpwnam(name) }
 }
 
-pub type Error = anyhow::Error; // TODO
+// Supplementary groups of a user, including the primary group
+pub fn getgrouplist(user: &CStr, gid: libc::gid_t) -> Vec<libc::gid_t> {
+    let mut groups: Vec<libc::gid_t> = vec![0; 32];
+
+    loop {
+        let mut len = groups.len() as libc::c_int;
+        let ret = unsafe { libc::getgrouplist(user.as_ptr(), gid, groups.as_mut_ptr(), &mut len) };
+
+        // On failure, len is set to the required size
+        groups.resize(len.max(0) as usize, 0);
+        if ret != -1 {
+            return groups;
+        }
+    }
+}
+
+// Re-exported, so callers can match on Error::code
+pub use pam_sys::PamReturnCode;
+
 type Result<T, E = Error> = std::result::Result<T, E>;
 
 pub struct Pam {
     handle: *mut RawPamHandle,
     last_code: PamReturnCode,
 
-    _conversation: PamConversation,
+    // Referenced by PAM until pam_end, which runs before fields are dropped (see Drop)
+    _conversation: Option<Pin<Box<PamConversationHandler>>>,
 
     // NOTE: i did not find any reason for this flag to be configurable per-call
     // however, that can trivially be done
     silent: bool,
 }
 
+// Safety: a PAM handle is not tied to the thread that started it,
+// and every call goes through &mut self, so there is never more than one thread using it.
+// The conversation may be called from any of those threads, hence PamDisplay + Send.
+unsafe impl Send for Pam {}
+
 // NOTE: we are using the raw api, since the flag definitions in pam_sys::wrapped are too inflexible
 // (and some stuff is broken)
 // TODO: consider upstreaming?
@@ -45,44 +79,112 @@ macro_rules! pam_call {
             unsafe { sys::$method($self.handle, $($args)* ) }
         );
 
-        let $ret = $self.handle_ret(code);
+        let $ret = $self.handle_ret(stringify!($method), code);
     };
 }
 
+// Linux-PAM 1.4+, not in pam_sys. Only tests need it.
+#[cfg(any(test, feature = "stub"))]
+unsafe extern "C" {
+    fn pam_start_confdir(
+        service_name: *const c_char,
+        user: *const c_char,
+        pam_conversation: *const PamConversation,
+        confdir: *const c_char,
+        pamh: *mut *mut RawPamHandle,
+    ) -> c_int;
+}
+
+#[cfg(any(test, feature = "stub"))]
+fn start_confdir(
+    service_name: &str,
+    confdir: &Path,
+    username: Option<&str>,
+    conversation: &PamConversation,
+    handle: &mut *mut RawPamHandle,
+) -> Result<PamReturnCode> {
+    let nul = |_| Error::InvalidData("argument to pam_start contains a NUL byte");
+
+    let service_name = CString::new(service_name).map_err(nul)?;
+    let confdir = CString::new(confdir.as_os_str().as_bytes()).map_err(nul)?;
+    let username = username.map(CString::new).transpose().map_err(nul)?;
+
+    let code = unsafe {
+        pam_start_confdir(
+            service_name.as_ptr(),
+            username.as_ref().map_or(ptr::null(), |x| x.as_ptr()),
+            conversation,
+            confdir.as_ptr(),
+            handle,
+        )
+    };
+
+    Ok(PamReturnCode::from(code))
+}
+
 impl Pam {
     pub fn new(
         service_name: &str,
-        display: Option<impl PamDisplay>,
+        display: Option<impl PamDisplay + Send + 'static>,
 
         // If None, PAM will query for it via prompt() on PamDisplay
         username: Option<&str>,
     ) -> Result<Self> {
-        let handle: *mut RawPamHandle = ptr::null_mut();
+        Self::start(display, |conversation, handle| {
+            Ok(pam_sys::start(service_name, username, conversation, handle))
+        })
+    }
+
+    // Reads the service from confdir instead of /etc/pam.d, see stub
+    #[cfg(any(test, feature = "stub"))]
+    pub fn with_confdir(
+        service_name: &str,
+        confdir: &Path,
+        display: Option<impl PamDisplay + Send + 'static>,
+        username: Option<&str>,
+    ) -> Result<Self> {
+        Self::start(display, |conversation, handle| {
+            start_confdir(service_name, confdir, username, conversation, handle)
+        })
+    }
+
+    // begin calls pam_start, or a variant of it
+    fn start(
+        display: Option<impl PamDisplay + Send + 'static>,
+        begin: impl FnOnce(&PamConversation, &mut *mut RawPamHandle) -> Result<PamReturnCode>,
+    ) -> Result<Self> {
+        let mut handle: *mut RawPamHandle = ptr::null_mut();
 
         let silent = display.is_none();
-        let conversation = display
-            .map(|v| converse::new(v))
-            .unwrap_or(converse::none());
+        let handler = display.map(PamConversationHandler::new);
 
-        match pam_sys::start(service_name, username, &conversation, handle as _) {
+        // PAM copies this, but not what data_ptr points to
+        let conversation = match &handler {
+            Some(handler) => handler.as_ref().conversation(),
+            None => converse::none(),
+        };
+
+        let code = begin(&conversation, &mut handle)?;
+
+        match code {
             PamReturnCode::SUCCESS => Ok(Self {
-                _conversation: conversation,
+                _conversation: handler,
 
-                // Safety: PAM is expected to fill the handle after we call start
-                handle: unsafe { &mut *handle },
+                // PAM fills this in on success
+                handle,
 
                 last_code: PamReturnCode::SUCCESS,
                 silent,
             }),
-            err => return Err(anyhow!(err)),
+            err => Err(Error::call(ptr::null_mut(), "pam_start", err)),
         }
     }
 
-    fn handle_ret(&mut self, ret: PamReturnCode) -> Result<()> {
+    fn handle_ret(&mut self, operation: &'static str, ret: PamReturnCode) -> Result<()> {
         self.last_code = ret;
         match self.last_code {
             PamReturnCode::SUCCESS => Ok(()),
-            err => bail!(err),
+            err => Err(Error::call(self.handle, operation, err)),
         }
     }
 
@@ -106,6 +208,18 @@ impl Pam {
         ret
     }
 
+    // PAM prompts for the old and new password through PamDisplay.
+    // If only_expired is set, only tokens which have aged are changed.
+    pub fn change_auth_token(&mut self, only_expired: bool) -> Result<()> {
+        let flags = FlagsBuilder::new()
+            .set_if(self.silent, flags::SILENT)
+            .set_if(only_expired, flags::CHANGE_EXPIRED_AUTHTOK)
+            .finish();
+
+        pam_call!(let ret = self.pam_chauthtok(flags));
+        ret
+    }
+
     pub fn credentials(&mut self, op: CredentialsOP) -> Result<()> {
         let flags = FlagsBuilder::from(op.into())
             .set_if(self.silent, flags::SILENT)
@@ -149,7 +263,7 @@ impl Pam {
         let user = unsafe { CStr::from_ptr(user) };
 
         user.to_str()
-            .context("Cannot parse username")
+            .map_err(|_| Error::InvalidData("username is not valid UTF-8"))
             .map(|s| s.to_string())
     }
 
@@ -161,6 +275,46 @@ impl Pam {
         pam_call!(let ret = self.pam_misc_paste_env(env.as_ptr()));
         ret
     }
+
+    // Sets a single variable, which is often easier than building a diff
+    pub fn put_env(&mut self, key: &str, value: &str) -> Result<()> {
+        let pair = CString::new(format!("{key}={value}"))
+            .map_err(|_| Error::InvalidData("environment variable contains a NUL byte"))?;
+        pam_call!(let ret = self.pam_putenv(pair.as_ptr()));
+        ret
+    }
+
+    // The full PAM environment, to be passed to the session
+    pub fn env_list(&self) -> Vec<(OsString, OsString)> {
+        let list = unsafe { sys::pam_getenvlist(self.handle) };
+        if list.is_null() {
+            return Vec::new();
+        }
+
+        let mut env = Vec::new();
+        let mut i = 0;
+        loop {
+            let entry = unsafe { *list.offset(i) };
+            if entry.is_null() {
+                break;
+            }
+
+            let bytes = unsafe { CStr::from_ptr(entry) }.to_bytes();
+            if let Some(split) = bytes.iter().position(|b| *b == b'=') {
+                env.push((
+                    OsString::from_vec(bytes[..split].to_vec()),
+                    OsString::from_vec(bytes[split + 1..].to_vec()),
+                ));
+            }
+
+            // Both the entries and the list are ours to free
+            unsafe { libc::free(entry as *mut c_void) };
+            i += 1;
+        }
+
+        unsafe { libc::free(list as *mut c_void) };
+        env
+    }
 }
 
 impl Drop for Pam {
@@ -202,3 +356,133 @@ fn env_to_c_pointers(env: impl envy::diff::Diff) -> Vec<*const i8> {
         .chain(Some(ptr::null()))
         .collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        thread,
+    };
+
+    use super::*;
+    use crate::stub::StubService;
+
+    // Answers hidden prompts with the password, and keeps what PAM asked
+    struct Recorder {
+        password: Option<String>,
+        asked: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl PamDisplay for Recorder {
+        fn prompt(&self, text: &str, _show: bool) -> Result<String, ConversationError> {
+            self.asked.lock().unwrap().push(text.to_string());
+            self.password.clone().ok_or(ConversationError)
+        }
+
+        fn message(&self, _text: &str, _level: MessageLevel) -> Result<(), ConversationError> {
+            Ok(())
+        }
+    }
+
+    fn start(service: &StubService, password: Option<&str>) -> (Pam, Arc<Mutex<Vec<String>>>) {
+        let asked = Arc::default();
+        let display = Recorder {
+            password: password.map(String::from),
+            asked: Arc::clone(&asked),
+        };
+
+        let pam = service.start(Some(display), Some("alice")).unwrap();
+        (pam, asked)
+    }
+
+    #[test]
+    fn converses_after_the_handle_moves() {
+        let service = StubService::new("password=hunter2");
+        let (pam, asked) = start(&service, Some("hunter2"));
+
+        // PAM keeps a pointer to the conversation from pam_start on,
+        // which has to stay valid wherever the handle goes
+        let mut pam = Box::new(pam);
+        thread::spawn(move || pam.authenticate(false))
+            .join()
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(asked.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn fails_when_the_display_refuses() {
+        let service = StubService::new("password=hunter2");
+        let (mut pam, asked) = start(&service, None);
+
+        assert!(pam.authenticate(false).is_err());
+        assert_eq!(asked.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn fails_on_a_wrong_password() {
+        let service = StubService::new("password=hunter2");
+        let (mut pam, _) = start(&service, Some("hunter3"));
+
+        let error = pam.authenticate(false).unwrap_err();
+        assert_eq!(error.code(), Some(PamReturnCode::AUTH_ERR));
+    }
+
+    // Answers binary prompts of "ping" with "pong", and keeps the control bytes PAM sent
+    struct Pong(Arc<Mutex<Vec<u8>>>);
+
+    impl PamDisplay for Pong {
+        fn prompt(&self, _text: &str, _show: bool) -> Result<String, ConversationError> {
+            Err(ConversationError)
+        }
+
+        fn message(&self, _text: &str, _level: MessageLevel) -> Result<(), ConversationError> {
+            Ok(())
+        }
+
+        fn binary_prompt(
+            &self,
+            control: u8,
+            data: &[u8],
+        ) -> Result<(u8, Vec<u8>), ConversationError> {
+            self.0.lock().unwrap().push(control);
+            match data {
+                b"ping" => Ok((control, b"pong".to_vec())),
+                _ => Err(ConversationError),
+            }
+        }
+    }
+
+    #[test]
+    fn answers_binary_prompts() {
+        let service = StubService::new("binary=3");
+        let controls = Arc::default();
+        let display = Pong(Arc::clone(&controls));
+
+        let mut pam = service.start(Some(display), Some("alice")).unwrap();
+        pam.authenticate(false).unwrap();
+
+        assert_eq!(*controls.lock().unwrap(), [3]);
+    }
+
+    #[test]
+    fn refuses_binary_prompts_by_default() {
+        let service = StubService::new("binary=3");
+        let (mut pam, asked) = start(&service, Some("hunter2"));
+
+        let error = pam.authenticate(false).unwrap_err();
+        assert_eq!(error.code(), Some(PamReturnCode::CONV_ERR));
+        assert!(asked.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn fails_on_unknown_message_styles() {
+        let service = StubService::new("unknownstyle");
+        let (mut pam, asked) = start(&service, Some("hunter2"));
+
+        let error = pam.authenticate(false).unwrap_err();
+        assert_eq!(error.code(), Some(PamReturnCode::CONV_ERR));
+        assert!(asked.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,71 @@
+// A PAM service backed by the module in stub/pam_stub.c, for tests which need PAM to behave a certain way.
+// The module is built on the fly, which needs a C compiler and the PAM headers.
+use std::{fs, path::Path, process::Command};
+
+use tempfile::TempDir;
+
+use crate::{ConversationError, MessageLevel, Pam, PamDisplay, Result};
+
+const SERVICE: &str = "flint-stub";
+const SOURCE: &str = include_str!("../stub/pam_stub.c");
+
+pub struct StubService {
+    // Holds both the module and the service file
+    confdir: TempDir,
+}
+
+impl StubService {
+    // args configure the module, see stub/pam_stub.c
+    pub fn new(args: &str) -> Self {
+        let confdir = tempfile::tempdir().unwrap();
+        let source = confdir.path().join("pam_stub.c");
+        let module = confdir.path().join("pam_stub.so");
+
+        fs::write(&source, SOURCE).unwrap();
+
+        let status = Command::new("cc")
+            .args(["-shared", "-fPIC", "-o"])
+            .arg(&module)
+            .arg(&source)
+            .arg("-lpam")
+            .status()
+            .expect("Building the stub PAM module needs a C compiler");
+        assert!(status.success(), "Failed to build the stub PAM module");
+
+        let service: String = ["auth", "account", "password", "session"]
+            .iter()
+            .map(|kind| format!("{kind} required {} {args}\n", module.display()))
+            .collect();
+        fs::write(confdir.path().join(SERVICE), service).unwrap();
+
+        Self { confdir }
+    }
+
+    pub fn confdir(&self) -> &Path {
+        self.confdir.path()
+    }
+
+    pub fn start(
+        &self,
+        display: Option<impl PamDisplay + Send + 'static>,
+        username: Option<&str>,
+    ) -> Result<Pam> {
+        Pam::with_confdir(SERVICE, self.confdir(), display, username)
+    }
+}
+
+// Answers hidden prompts (i.e. for the password) with the given text, and refuses visible ones
+pub struct PasswordDisplay(pub String);
+
+impl PamDisplay for PasswordDisplay {
+    fn prompt(&self, _text: &str, show: bool) -> Result<String, ConversationError> {
+        match show {
+            false => Ok(self.0.clone()),
+            true => Err(ConversationError),
+        }
+    }
+
+    fn message(&self, _text: &str, _level: MessageLevel) -> Result<(), ConversationError> {
+        Ok(())
+    }
+}
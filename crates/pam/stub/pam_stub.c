@@ -0,0 +1,182 @@
+// A PAM module for tests, see src/stub.rs.
+// It is configured through its arguments, anything not asked for succeeds:
+//   password=<password>  authentication prompts for a password and fails on any other
+//   lowercase            folds the username to lowercase
+//   alias=<from>:<to>    renames user <from> to <to>, after folding
+//   binary=<control>     authentication sends a binary prompt of <control> and "ping",
+//                        and fails unless the answer is <control> and "pong"
+//   unknownstyle         authentication sends a message of a style no application knows
+//   expired              account management asks for a new password
+//   newpassword=<new>    changing the password prompts for it and fails on any other
+
+#include <ctype.h>
+#include <stdlib.h>
+#include <string.h>
+
+#include <security/pam_ext.h>
+#include <security/pam_modules.h>
+
+static const char *arg_value(int argc, const char **argv, const char *name) {
+    size_t len = strlen(name);
+
+    for (int i = 0; i < argc; i++) {
+        if (strncmp(argv[i], name, len) == 0 && argv[i][len] == '=')
+            return argv[i] + len + 1;
+    }
+
+    return NULL;
+}
+
+static int has_arg(int argc, const char **argv, const char *name) {
+    for (int i = 0; i < argc; i++) {
+        if (strcmp(argv[i], name) == 0)
+            return 1;
+    }
+
+    return 0;
+}
+
+// Like modules which map login names to accounts, i.e. case-insensitive directories
+static int rewrite_user(pam_handle_t *pamh, int argc, const char **argv) {
+    const char *user;
+    int ret = pam_get_user(pamh, &user, NULL);
+    if (ret != PAM_SUCCESS)
+        return ret;
+
+    // Setting PAM_USER frees what pam_get_user returned
+    char *canonical = strdup(user);
+    if (canonical == NULL)
+        return PAM_BUF_ERR;
+
+    if (has_arg(argc, argv, "lowercase")) {
+        for (char *c = canonical; *c; c++)
+            *c = tolower((unsigned char)*c);
+    }
+
+    const char *alias = arg_value(argc, argv, "alias");
+    const char *to = alias ? strchr(alias, ':') : NULL;
+
+    if (to != NULL && strlen(canonical) == (size_t)(to - alias) &&
+        strncmp(canonical, alias, to - alias) == 0) {
+        free(canonical);
+
+        canonical = strdup(to + 1);
+        if (canonical == NULL)
+            return PAM_BUF_ERR;
+    }
+
+    ret = pam_set_item(pamh, PAM_USER, canonical);
+    free(canonical);
+    return ret;
+}
+
+// Sends a single message through the conversation of the application
+static int converse(pam_handle_t *pamh, int style, const char *text, struct pam_response **resp) {
+    const struct pam_conv *conv;
+    int ret = pam_get_item(pamh, PAM_CONV, (const void **)&conv);
+    if (ret != PAM_SUCCESS)
+        return ret;
+    if (conv == NULL || conv->conv == NULL)
+        return PAM_CONV_ERR;
+
+    struct pam_message message = {.msg_style = style, .msg = text};
+    const struct pam_message *messages = &message;
+
+    *resp = NULL;
+    return conv->conv(1, &messages, resp, conv->appdata_ptr);
+}
+
+static void free_response(struct pam_response *resp) {
+    if (resp != NULL) {
+        free(resp->resp);
+        free(resp);
+    }
+}
+
+// Packets are a 4 byte big-endian length of the whole packet, a control byte, then data
+static int binary_prompt(pam_handle_t *pamh, int control) {
+    const unsigned char ping[] = {0, 0, 0, 9, control, 'p', 'i', 'n', 'g'};
+    const unsigned char pong[] = {0, 0, 0, 9, control, 'p', 'o', 'n', 'g'};
+
+    struct pam_response *resp;
+    int ret = converse(pamh, PAM_BINARY_PROMPT, (const char *)ping, &resp);
+    if (ret != PAM_SUCCESS) {
+        free_response(resp);
+        return ret;
+    }
+
+    // Only compare past the length once it is known to match
+    const unsigned char *reply = resp ? (const unsigned char *)resp->resp : NULL;
+    int matches = reply != NULL && memcmp(reply, pong, 4) == 0 && memcmp(reply, pong, sizeof(pong)) == 0;
+
+    free_response(resp);
+    return matches ? PAM_SUCCESS : PAM_AUTH_ERR;
+}
+
+PAM_EXTERN int pam_sm_authenticate(pam_handle_t *pamh, int flags, int argc, const char **argv) {
+    int ret = rewrite_user(pamh, argc, argv);
+    if (ret != PAM_SUCCESS)
+        return ret;
+
+    const char *control = arg_value(argc, argv, "binary");
+    if (control != NULL) {
+        ret = binary_prompt(pamh, atoi(control));
+        if (ret != PAM_SUCCESS)
+            return ret;
+    }
+
+    // Without any text, which only a text style would have
+    if (has_arg(argc, argv, "unknownstyle")) {
+        struct pam_response *resp;
+        ret = converse(pamh, 42, NULL, &resp);
+        free_response(resp);
+        if (ret != PAM_SUCCESS)
+            return ret;
+    }
+
+    const char *expected = arg_value(argc, argv, "password");
+    if (expected == NULL)
+        return PAM_SUCCESS;
+
+    // Goes through the conversation of the application
+    const char *password;
+    ret = pam_get_authtok(pamh, PAM_AUTHTOK, &password, NULL);
+    if (ret != PAM_SUCCESS)
+        return ret;
+
+    return strcmp(password, expected) == 0 ? PAM_SUCCESS : PAM_AUTH_ERR;
+}
+
+PAM_EXTERN int pam_sm_setcred(pam_handle_t *pamh, int flags, int argc, const char **argv) {
+    return PAM_SUCCESS;
+}
+
+PAM_EXTERN int pam_sm_acct_mgmt(pam_handle_t *pamh, int flags, int argc, const char **argv) {
+    return has_arg(argc, argv, "expired") ? PAM_NEW_AUTHTOK_REQD : PAM_SUCCESS;
+}
+
+PAM_EXTERN int pam_sm_chauthtok(pam_handle_t *pamh, int flags, int argc, const char **argv) {
+    // Called twice, the first time only to check that a change is possible
+    if (flags & PAM_PRELIM_CHECK)
+        return PAM_SUCCESS;
+
+    const char *expected = arg_value(argc, argv, "newpassword");
+    if (expected == NULL)
+        return PAM_SUCCESS;
+
+    // Prompts for the new password, and again to confirm it
+    const char *password;
+    int ret = pam_get_authtok(pamh, PAM_AUTHTOK, &password, NULL);
+    if (ret != PAM_SUCCESS)
+        return ret;
+
+    return strcmp(password, expected) == 0 ? PAM_SUCCESS : PAM_AUTHTOK_ERR;
+}
+
+PAM_EXTERN int pam_sm_open_session(pam_handle_t *pamh, int flags, int argc, const char **argv) {
+    return PAM_SUCCESS;
+}
+
+PAM_EXTERN int pam_sm_close_session(pam_handle_t *pamh, int flags, int argc, const char **argv) {
+    return PAM_SUCCESS;
+}